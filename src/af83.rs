@@ -35,6 +35,7 @@ mod tests {
             repo: "sybil-system".to_owned(),
             sha: "159f8769b897ed7774700d0b2777def8ac838b8f".to_owned(),
             branch: "5912-make-logo-bigger".to_owned(),
+            ..CommitRef::test_default()
         };
 
         assert_eq!(
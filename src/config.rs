@@ -0,0 +1,1307 @@
+// Copyright © 2017 Teddy Wing
+//
+// This file is part of Kipper.
+//
+// Kipper is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Kipper is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kipper. If not, see <http://www.gnu.org/licenses/>.
+
+extern crate serde;
+extern crate toml;
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use self::serde::{Serialize, Serializer};
+
+use github::{CommitStatus, GitHubApp};
+use jenkins::{Job, JobStatus};
+use pull_request::ForkPolicy;
+use job_locator::{BranchesProject, FolderPath, JobLocator};
+use job_matcher::{Af83, JobMatcher, RegexMatcher, RevisionMatcher, TemplateMatcher};
+use reporter::ReporterKind;
+
+const DEFAULT_PORT: u16 = 8000;
+const DEFAULT_GITHUB_API_URL: &str = "https://api.github.com";
+const DEFAULT_WORKERS: usize = 8;
+const DEFAULT_QUEUE_LIMIT: usize = 1000;
+const DEFAULT_JENKINS_MAX_CONCURRENT_REQUESTS: usize = 4;
+
+// In seconds.
+const DEFAULT_POLL_INTERVAL: u64 = 30;
+const DEFAULT_WARM_UP: u64 = 30;
+const DEFAULT_TIMEOUT: u64 = 20 * 60;
+const DEFAULT_DISCOVERY_TIMEOUT: u64 = 10 * 60;
+
+// Settings are collected from several sources. When the same setting comes
+// from more than one source, the first of these wins:
+//
+//   1. command line flags
+//   2. environment variables
+//   3. the configuration file
+//   4. defaults
+//
+// Secrets can be given either directly or as a path to a file containing
+// them (`token` or `token_file`). Giving both in the same source is an error.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    pub port: Option<u16>,
+    pub state_file: Option<PathBuf>,
+    pub workers: Option<usize>,
+    pub queue_limit: Option<usize>,
+
+    #[serde(default)]
+    pub jenkins: JenkinsSettings,
+
+    #[serde(default)]
+    pub github: GitHubSettings,
+
+    #[serde(default)]
+    pub gitlab: GitLabSettings,
+
+    // Per-repository settings, keyed by "owner/repo". Only read from the
+    // configuration file.
+    #[serde(default)]
+    pub repos: BTreeMap<String, RepoSettings>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JenkinsSettings {
+    pub url: Option<String>,
+    pub user_id: Option<String>,
+    pub token: Option<String>,
+    pub token_file: Option<PathBuf>,
+    pub notification_token: Option<String>,
+    pub notification_token_file: Option<PathBuf>,
+    pub max_concurrent_requests: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GitHubSettings {
+    pub api_url: Option<String>,
+    pub token: Option<String>,
+    pub token_file: Option<PathBuf>,
+    pub app_id: Option<u64>,
+    pub private_key: Option<String>,
+    pub private_key_file: Option<PathBuf>,
+    pub webhook_secret: Option<String>,
+    pub webhook_secret_file: Option<PathBuf>,
+    pub report_superseded: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GitLabSettings {
+    pub url: Option<String>,
+    pub token: Option<String>,
+    pub token_file: Option<PathBuf>,
+    pub webhook_secret: Option<String>,
+    pub webhook_secret_file: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RepoSettings {
+    pub reporter: Option<String>,
+    pub job_path: Option<String>,
+    pub job_name_template: Option<String>,
+    pub job_name_regex: Option<String>,
+    pub trigger: Option<bool>,
+    pub untrusted_forks: Option<String>,
+    pub fork_label: Option<String>,
+    pub skip_drafts: Option<bool>,
+    pub run_labels: Option<Vec<String>>,
+    pub track_tags: Option<bool>,
+    pub poll_interval: Option<u64>,
+    pub warm_up: Option<u64>,
+    pub discovery_timeout: Option<u64>,
+    pub timeout: Option<u64>,
+    pub timeout_factor: Option<f64>,
+
+    // Commit statuses to post for Jenkins build results, e.g.
+    // `UNSTABLE = "success"`.
+    #[serde(default)]
+    pub results: BTreeMap<String, String>,
+}
+
+impl Settings {
+    pub fn from_file(path: &Path) -> Result<Settings, Box<dyn Error>> {
+        let mut contents = String::new();
+        File::open(path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?
+            .read_to_string(&mut contents)?;
+
+        let settings: Settings = toml::from_str(&contents)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        // Relative paths in the file are relative to the file, not to
+        // wherever Kipper was started from.
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+
+        settings.resolve_paths(base).read_secret_files()
+    }
+
+    // Takes a function to look up variables so tests don't have to modify
+    // the process environment. Pass `|v| std::env::var(v).ok()`.
+    pub fn from_env<F>(var: F) -> Result<Settings, Box<dyn Error>>
+    where F: Fn(&str) -> Option<String> {
+        let port = match var("KIPPER_PORT") {
+            Some(p) => Some(
+                p.parse()
+                    .map_err(|e| format!("KIPPER_PORT: {}", e))?
+            ),
+            None => None,
+        };
+
+        let settings = Settings {
+            port,
+            state_file: var("KIPPER_STATE_FILE").map(PathBuf::from),
+            workers: parse_var(&var, "KIPPER_WORKERS")?,
+            queue_limit: parse_var(&var, "KIPPER_QUEUE_LIMIT")?,
+            jenkins: JenkinsSettings {
+                url: var("KIPPER_JENKINS_URL"),
+                user_id: var("KIPPER_JENKINS_USER_ID"),
+                token: var("KIPPER_JENKINS_TOKEN"),
+                token_file: var("KIPPER_JENKINS_TOKEN_FILE").map(PathBuf::from),
+                notification_token: var("KIPPER_JENKINS_NOTIFICATION_TOKEN"),
+                notification_token_file: var("KIPPER_JENKINS_NOTIFICATION_TOKEN_FILE")
+                    .map(PathBuf::from),
+                max_concurrent_requests: parse_var(
+                    &var,
+                    "KIPPER_JENKINS_MAX_CONCURRENT_REQUESTS"
+                )?,
+            },
+            github: GitHubSettings {
+                api_url: var("KIPPER_GITHUB_API_URL"),
+                token: var("KIPPER_GITHUB_TOKEN"),
+                token_file: var("KIPPER_GITHUB_TOKEN_FILE").map(PathBuf::from),
+                app_id: parse_var(&var, "KIPPER_GITHUB_APP_ID")?,
+                private_key: var("KIPPER_GITHUB_PRIVATE_KEY"),
+                private_key_file: var("KIPPER_GITHUB_PRIVATE_KEY_FILE")
+                    .map(PathBuf::from),
+                webhook_secret: var("KIPPER_GITHUB_WEBHOOK_SECRET"),
+                webhook_secret_file: var("KIPPER_GITHUB_WEBHOOK_SECRET_FILE")
+                    .map(PathBuf::from),
+                report_superseded: match var("KIPPER_GITHUB_REPORT_SUPERSEDED") {
+                    Some(b) => Some(
+                        b.parse()
+                            .map_err(|e| {
+                                format!("KIPPER_GITHUB_REPORT_SUPERSEDED: {}", e)
+                            })?
+                    ),
+                    None => None,
+                },
+            },
+            gitlab: GitLabSettings {
+                url: var("KIPPER_GITLAB_URL"),
+                token: var("KIPPER_GITLAB_TOKEN"),
+                token_file: var("KIPPER_GITLAB_TOKEN_FILE").map(PathBuf::from),
+                webhook_secret: var("KIPPER_GITLAB_WEBHOOK_SECRET"),
+                webhook_secret_file: var("KIPPER_GITLAB_WEBHOOK_SECRET_FILE")
+                    .map(PathBuf::from),
+            },
+            repos: BTreeMap::new(),
+        };
+
+        settings.read_secret_files()
+    }
+
+    // Make relative paths relative to `base`, the configuration file's
+    // directory.
+    fn resolve_paths(mut self, base: &Path) -> Settings {
+        let resolve = |path: &mut Option<PathBuf>| {
+            if let Some(ref mut path) = *path {
+                if path.is_relative() {
+                    *path = base.join(&path);
+                }
+            }
+        };
+
+        resolve(&mut self.state_file);
+        resolve(&mut self.jenkins.token_file);
+        resolve(&mut self.jenkins.notification_token_file);
+        resolve(&mut self.github.token_file);
+        resolve(&mut self.github.private_key_file);
+        resolve(&mut self.github.webhook_secret_file);
+        resolve(&mut self.gitlab.token_file);
+        resolve(&mut self.gitlab.webhook_secret_file);
+
+        self
+    }
+
+    // Replace `*_file` settings with the contents of those files.
+    pub fn read_secret_files(mut self) -> Result<Settings, Box<dyn Error>> {
+        self.jenkins.token = read_secret(
+            "jenkins.token",
+            self.jenkins.token,
+            self.jenkins.token_file.take(),
+        )?;
+        self.jenkins.notification_token = read_secret(
+            "jenkins.notification_token",
+            self.jenkins.notification_token,
+            self.jenkins.notification_token_file.take(),
+        )?;
+        self.github.token = read_secret(
+            "github.token",
+            self.github.token,
+            self.github.token_file.take(),
+        )?;
+        self.github.private_key = read_secret(
+            "github.private_key",
+            self.github.private_key,
+            self.github.private_key_file.take(),
+        )?;
+        self.github.webhook_secret = read_secret(
+            "github.webhook_secret",
+            self.github.webhook_secret,
+            self.github.webhook_secret_file.take(),
+        )?;
+        self.gitlab.token = read_secret(
+            "gitlab.token",
+            self.gitlab.token,
+            self.gitlab.token_file.take(),
+        )?;
+        self.gitlab.webhook_secret = read_secret(
+            "gitlab.webhook_secret",
+            self.gitlab.webhook_secret,
+            self.gitlab.webhook_secret_file.take(),
+        )?;
+
+        Ok(self)
+    }
+
+    // Fill in any settings missing from `self` with those from `lower`.
+    pub fn merge(self, lower: Settings) -> Settings {
+        let mut repos = lower.repos;
+        repos.extend(self.repos);
+
+        Settings {
+            port: self.port.or(lower.port),
+            state_file: self.state_file.or(lower.state_file),
+            workers: self.workers.or(lower.workers),
+            queue_limit: self.queue_limit.or(lower.queue_limit),
+            jenkins: JenkinsSettings {
+                url: self.jenkins.url.or(lower.jenkins.url),
+                user_id: self.jenkins.user_id.or(lower.jenkins.user_id),
+                token: self.jenkins.token.or(lower.jenkins.token),
+                token_file: self.jenkins.token_file.or(lower.jenkins.token_file),
+                notification_token: self.jenkins.notification_token
+                    .or(lower.jenkins.notification_token),
+                notification_token_file: self.jenkins.notification_token_file
+                    .or(lower.jenkins.notification_token_file),
+                max_concurrent_requests: self.jenkins.max_concurrent_requests
+                    .or(lower.jenkins.max_concurrent_requests),
+            },
+            github: GitHubSettings {
+                api_url: self.github.api_url.or(lower.github.api_url),
+                token: self.github.token.or(lower.github.token),
+                token_file: self.github.token_file.or(lower.github.token_file),
+                app_id: self.github.app_id.or(lower.github.app_id),
+                private_key: self.github.private_key.or(lower.github.private_key),
+                private_key_file: self.github.private_key_file
+                    .or(lower.github.private_key_file),
+                webhook_secret: self.github.webhook_secret
+                    .or(lower.github.webhook_secret),
+                webhook_secret_file: self.github.webhook_secret_file
+                    .or(lower.github.webhook_secret_file),
+                report_superseded: self.github.report_superseded
+                    .or(lower.github.report_superseded),
+            },
+            gitlab: GitLabSettings {
+                url: self.gitlab.url.or(lower.gitlab.url),
+                token: self.gitlab.token.or(lower.gitlab.token),
+                token_file: self.gitlab.token_file.or(lower.gitlab.token_file),
+                webhook_secret: self.gitlab.webhook_secret
+                    .or(lower.gitlab.webhook_secret),
+                webhook_secret_file: self.gitlab.webhook_secret_file
+                    .or(lower.gitlab.webhook_secret_file),
+            },
+            repos,
+        }
+    }
+}
+
+fn parse_var<T, F>(var: &F, name: &str) -> Result<Option<T>, Box<dyn Error>>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+    F: Fn(&str) -> Option<String>,
+{
+    match var(name) {
+        Some(value) => Ok(
+            Some(
+                value.parse()
+                    .map_err(|e| format!("{}: {}", name, e))?
+            )
+        ),
+        None => Ok(None),
+    }
+}
+
+fn read_secret(
+    name: &str,
+    value: Option<String>,
+    file: Option<PathBuf>,
+) -> Result<Option<String>, Box<dyn Error>> {
+    match (value, file) {
+        (Some(_), Some(_)) => Err(
+            format!("only one of `{0}` and `{0}_file` may be given", name).into()
+        ),
+        (Some(v), None) => Ok(Some(v)),
+        (None, Some(path)) => {
+            let mut contents = String::new();
+            File::open(&path)
+                .map_err(|e| format!("{}_file {}: {}", name, path.display(), e))?
+                .read_to_string(&mut contents)?;
+
+            Ok(Some(contents.trim().to_owned()))
+        },
+        (None, None) => Ok(None),
+    }
+}
+
+// A value that should never end up in logs or in `check-config` output.
+#[derive(Clone, PartialEq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: String) -> Secret {
+        Secret(value)
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret(<redacted>)")
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str("<redacted>")
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Config {
+    pub port: u16,
+
+    // Journal of running trackers. Without it, tracking state is lost when
+    // Kipper stops.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_file: Option<PathBuf>,
+
+    // Threads polling Jenkins for the trackers' builds.
+    pub workers: usize,
+
+    // Webhooks are refused with a 503 when this many trackers are running.
+    pub queue_limit: usize,
+
+    pub jenkins: JenkinsConfig,
+    pub github: GitHubConfig,
+
+    // Only needed for projects hosted on GitLab.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gitlab: Option<GitLabConfig>,
+
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub repos: BTreeMap<String, RepoConfig>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct JenkinsConfig {
+    pub url: String,
+    pub user_id: String,
+    pub token: Secret,
+
+    // Required in the `token` query parameter of build notifications.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notification_token: Option<Secret>,
+
+    // Requests to the Jenkins server that can be in flight at once.
+    pub max_concurrent_requests: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct GitHubConfig {
+    // `https://[hostname]/api/v3` for GitHub Enterprise Server.
+    pub api_url: String,
+
+    // A personal access token, used when Kipper isn't authenticated as a
+    // GitHub App or the webhook didn't come from an app installation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<Secret>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app: Option<GitHubApp>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook_secret: Option<Secret>,
+
+    // Post an error status on commits whose tracking was cancelled because a
+    // newer commit was pushed to the pull request.
+    pub report_superseded: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct GitLabConfig {
+    pub url: String,
+    pub token: Secret,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook_secret: Option<Secret>,
+}
+
+impl GitLabConfig {
+    fn from_settings(
+        settings: GitLabSettings,
+    ) -> Result<Option<GitLabConfig>, Box<dyn Error>> {
+        if settings.url.is_none() && settings.token.is_none() {
+            return Ok(None)
+        }
+
+        Ok(
+            Some(
+                GitLabConfig {
+                    url: required("gitlab.url", settings.url)?
+                        .trim_end_matches('/')
+                        .to_owned(),
+                    token: Secret(required("gitlab.token", settings.token)?),
+                    webhook_secret: settings.webhook_secret.map(Secret),
+                }
+            )
+        )
+    }
+}
+
+// Repositories without a `[repos."owner/repo"]` section use the defaults.
+#[derive(Clone, Debug, Serialize)]
+pub struct RepoConfig {
+    // Whether to post commit statuses or check runs.
+    pub reporter: ReporterKind,
+
+    // The Jenkins job holding the repository's builds, with a segment per
+    // folder, e.g. `{owner}/{repo}/PR-{number}`. Defaults to the
+    // `{repo}-branches` project.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub job_path: Option<String>,
+
+    // How Jenkins builds are matched to commits. Without either setting, the
+    // af83 convention, `{branch}-{sha:5}`, is used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub job_name_template: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub job_name_regex: Option<String>,
+
+    // Whether Kipper starts builds itself, with `buildWithParameters`,
+    // rather than waiting for Jenkins to notice new commits.
+    pub trigger: bool,
+
+    // Whether to track pull requests from forks by authors without write
+    // access, and the label that allows it with the `label` policy.
+    pub untrusted_forks: ForkPolicy,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fork_label: Option<String>,
+
+    // Whether to wait for draft pull requests to be marked ready for review.
+    pub skip_drafts: bool,
+
+    // Labels that start a build of the head commit when added.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub run_labels: Vec<String>,
+
+    // Whether to follow builds of pushed tags, as well as branches.
+    pub track_tags: bool,
+
+    // Seconds between checks of a build's status.
+    pub poll_interval: u64,
+
+    // Seconds to give Jenkins to create a build for a new commit before
+    // looking for it.
+    pub warm_up: u64,
+
+    // Seconds after the webhook arrives to keep looking for a build, after
+    // which an error status is posted.
+    pub discovery_timeout: u64,
+
+    // Seconds after a build is found to give up on it, unless
+    // `timeout_factor` is set and Jenkins can estimate the build's duration,
+    // in which case it's that estimate multiplied by the factor.
+    pub timeout: u64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_factor: Option<f64>,
+
+    // Commit statuses for build results, by their Jenkins name, where they
+    // differ from the defaults.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub results: BTreeMap<String, CommitStatus>,
+}
+
+impl Default for RepoConfig {
+    fn default() -> RepoConfig {
+        RepoConfig {
+            reporter: ReporterKind::default(),
+            job_path: None,
+            job_name_template: None,
+            job_name_regex: None,
+            trigger: false,
+            untrusted_forks: ForkPolicy::default(),
+            fork_label: None,
+            skip_drafts: false,
+            run_labels: Vec::new(),
+            track_tags: false,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            warm_up: DEFAULT_WARM_UP,
+            discovery_timeout: DEFAULT_DISCOVERY_TIMEOUT,
+            timeout: DEFAULT_TIMEOUT,
+            timeout_factor: None,
+            results: BTreeMap::new(),
+        }
+    }
+}
+
+impl RepoConfig {
+    fn from_settings(
+        name: &str,
+        settings: RepoSettings,
+    ) -> Result<RepoConfig, Box<dyn Error>> {
+        let reporter = match settings.reporter {
+            Some(r) => r.parse()
+                .map_err(|e| format!("repos.\"{}\": {}", name, e))?,
+            None => ReporterKind::default(),
+        };
+
+        let untrusted_forks = match settings.untrusted_forks {
+            Some(p) => p.parse()
+                .map_err(|e| format!("repos.\"{}\": {}", name, e))?,
+            None => ForkPolicy::default(),
+        };
+
+        let mut results = BTreeMap::new();
+        for (result, status) in settings.results {
+            JobStatus::from_str(&result)
+                .map_err(|e| format!("repos.\"{}\".results: {}", name, e))?;
+
+            let status = match CommitStatus::from_str(&status) {
+                Ok(CommitStatus::Pending) | Err(_) => return Err(
+                    format!(
+                        "repos.\"{}\".results.{}: expected 'success', \
+                            'failure' or 'error', got '{}'",
+                        name,
+                        result,
+                        status
+                    ).into()
+                ),
+                Ok(status) => status,
+            };
+
+            results.insert(result, status);
+        }
+
+        let repo = RepoConfig {
+            reporter,
+            job_path: settings.job_path,
+            job_name_template: settings.job_name_template,
+            job_name_regex: settings.job_name_regex,
+            trigger: settings.trigger.unwrap_or(false),
+            untrusted_forks,
+            fork_label: settings.fork_label,
+            skip_drafts: settings.skip_drafts.unwrap_or(false),
+            run_labels: settings.run_labels.unwrap_or_default(),
+            track_tags: settings.track_tags.unwrap_or(false),
+            poll_interval: settings.poll_interval.unwrap_or(DEFAULT_POLL_INTERVAL),
+            warm_up: settings.warm_up.unwrap_or(DEFAULT_WARM_UP),
+            discovery_timeout: settings.discovery_timeout
+                .unwrap_or(DEFAULT_DISCOVERY_TIMEOUT),
+            timeout: settings.timeout.unwrap_or(DEFAULT_TIMEOUT),
+            timeout_factor: settings.timeout_factor,
+            results,
+        };
+
+        if repo.poll_interval == 0 {
+            return Err(
+                format!("repos.\"{}\": `poll_interval` must be positive", name)
+                    .into()
+            )
+        }
+
+        if repo.timeout_factor.is_some_and(|f| !(f > 0.0 && f.is_finite())) {
+            return Err(
+                format!("repos.\"{}\": `timeout_factor` must be positive", name)
+                    .into()
+            )
+        }
+
+        if repo.untrusted_forks == ForkPolicy::Label && repo.fork_label.is_none() {
+            return Err(
+                format!(
+                    "repos.\"{}\": `untrusted_forks = \"label\"` requires \
+                        `fork_label`",
+                    name
+                ).into()
+            )
+        }
+
+        if repo.job_name_template.is_some() && repo.job_name_regex.is_some() {
+            return Err(
+                format!(
+                    "repos.\"{}\": only one of `job_name_template` and \
+                        `job_name_regex` may be given",
+                    name
+                ).into()
+            )
+        }
+
+        repo.job_locator()
+            .map_err(|e| format!("repos.\"{}\": {}", name, e))?;
+        repo.job_matcher()
+            .map_err(|e| format!("repos.\"{}\": {}", name, e))?;
+
+        Ok(repo)
+    }
+
+    // The status to post on a commit for a build result.
+    pub fn commit_status(&self, result: &JobStatus) -> CommitStatus {
+        result.name()
+            .and_then(|name| self.results.get(name))
+            .cloned()
+            .unwrap_or_else(|| result.commit_status())
+    }
+
+    // Seconds to wait for `job` to finish, counted from when it was found.
+    pub fn timeout_for(&self, job: &Job) -> u64 {
+        match self.timeout_factor {
+            Some(factor) if job.estimated_duration > 0 => {
+                (job.estimated_duration as f64 / 1000.0 * factor).ceil() as u64
+            },
+            _ => self.timeout,
+        }
+    }
+
+    pub fn job_locator(&self) -> Result<Box<dyn JobLocator>, Box<dyn Error>> {
+        match self.job_path {
+            Some(ref path) => Ok(Box::new(FolderPath::new(path)?)),
+            None => Ok(Box::new(BranchesProject)),
+        }
+    }
+
+    pub fn job_matcher(&self) -> Result<Box<dyn JobMatcher>, Box<dyn Error>> {
+        if let Some(ref template) = self.job_name_template {
+            return Ok(Box::new(TemplateMatcher::new(template)?))
+        }
+
+        if let Some(ref pattern) = self.job_name_regex {
+            return Ok(Box::new(RegexMatcher::new(pattern)?))
+        }
+
+        Ok(Box::new(RevisionMatcher::new(Af83)))
+    }
+}
+
+impl Config {
+    pub fn from_settings(settings: Settings) -> Result<Config, Box<dyn Error>> {
+        let mut repos = BTreeMap::new();
+        for (name, repo) in settings.repos {
+            let repo = RepoConfig::from_settings(&name, repo)?;
+            repos.insert(name, repo);
+        }
+
+        let app = match (settings.github.app_id, settings.github.private_key) {
+            (Some(app_id), Some(private_key)) => Some(
+                GitHubApp::new(app_id, Secret(private_key))?
+            ),
+            (Some(_), None) => return Err(
+                "missing required setting `github.private_key`".into()
+            ),
+            (None, Some(_)) => return Err(
+                "missing required setting `github.app_id`".into()
+            ),
+            (None, None) => None,
+        };
+
+        if app.is_none() {
+            required("github.token", settings.github.token.clone())?;
+        }
+
+        Ok(
+            Config {
+                port: settings.port.unwrap_or(DEFAULT_PORT),
+                state_file: settings.state_file,
+                workers: positive(
+                    "workers",
+                    settings.workers.unwrap_or(DEFAULT_WORKERS)
+                )?,
+                queue_limit: positive(
+                    "queue_limit",
+                    settings.queue_limit.unwrap_or(DEFAULT_QUEUE_LIMIT)
+                )?,
+                jenkins: JenkinsConfig {
+                    url: required("jenkins.url", settings.jenkins.url)?
+                        .trim_end_matches('/')
+                        .to_owned(),
+                    user_id: required("jenkins.user_id", settings.jenkins.user_id)?,
+                    token: Secret(
+                        required("jenkins.token", settings.jenkins.token)?
+                    ),
+                    notification_token: settings.jenkins.notification_token
+                        .map(Secret),
+                    max_concurrent_requests: positive(
+                        "jenkins.max_concurrent_requests",
+                        settings.jenkins.max_concurrent_requests
+                            .unwrap_or(DEFAULT_JENKINS_MAX_CONCURRENT_REQUESTS)
+                    )?,
+                },
+                github: GitHubConfig {
+                    api_url: settings.github.api_url
+                        .unwrap_or_else(|| DEFAULT_GITHUB_API_URL.to_owned())
+                        .trim_end_matches('/')
+                        .to_owned(),
+                    token: settings.github.token.map(Secret),
+                    app,
+                    webhook_secret: settings.github.webhook_secret.map(Secret),
+                    report_superseded: settings.github.report_superseded
+                        .unwrap_or(false),
+                },
+                gitlab: GitLabConfig::from_settings(settings.gitlab)?,
+                repos,
+            }
+        )
+    }
+
+    pub fn repo(&self, owner: &str, repo: &str) -> RepoConfig {
+        self.repos.get(&format!("{}/{}", owner, repo))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // The effective configuration as TOML, with secrets redacted.
+    pub fn to_redacted_toml(&self) -> Result<String, Box<dyn Error>> {
+        Ok(toml::to_string(self)?)
+    }
+}
+
+fn positive(name: &str, value: usize) -> Result<usize, Box<dyn Error>> {
+    if value == 0 {
+        return Err(format!("setting `{}` must be positive", name).into())
+    }
+
+    Ok(value)
+}
+
+fn required(name: &str, value: Option<String>) -> Result<String, Box<dyn Error>> {
+    match value {
+        Some(ref v) if v.is_empty() => Err(
+            format!("setting `{}` must not be empty", name).into()
+        ),
+        Some(v) => Ok(v),
+        None => Err(format!("missing required setting `{}`", name).into()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use jenkins::JobStatus;
+
+    use super::*;
+
+    // A new directory for each call, so that tests running in parallel, or
+    // in several processes at once, don't overwrite each other's files.
+    fn temp_dir() -> PathBuf {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let dir = env::temp_dir().join(
+            format!(
+                "kipper-config-test-{}-{}",
+                process::id(),
+                COUNT.fetch_add(1, Ordering::SeqCst)
+            )
+        );
+        fs::create_dir_all(&dir).expect("Failed to create temporary directory");
+
+        dir
+    }
+
+    fn write_temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = temp_dir().join(name);
+        fs::write(&path, contents).expect("Failed to write temporary file");
+
+        path
+    }
+
+    fn complete_settings() -> Settings {
+        Settings {
+            port: None,
+            state_file: None,
+            workers: None,
+            queue_limit: None,
+            jenkins: JenkinsSettings {
+                url: Some("https://jenkins.example.com/".to_owned()),
+                user_id: Some("username".to_owned()),
+                token: Some("jenkins-token".to_owned()),
+                ..JenkinsSettings::default()
+            },
+            github: GitHubSettings {
+                token: Some("github-token".to_owned()),
+                ..GitHubSettings::default()
+            },
+            gitlab: GitLabSettings::default(),
+            repos: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn settings_from_file_parses_toml() {
+        let token_path = write_temp_file("github-token", "github-token\n");
+        let config_path = write_temp_file(
+            "parses-toml.toml",
+            &format!(r#"
+                port = 8080
+
+                [jenkins]
+                url = "https://jenkins.example.com"
+                user_id = "username"
+                token = "jenkins-token"
+
+                [github]
+                token_file = "{}"
+            "#, token_path.display())
+        );
+
+        let settings = Settings::from_file(&config_path)
+            .expect("Failed to read settings");
+
+        assert_eq!(settings.port, Some(8080));
+        assert_eq!(
+            settings.jenkins.url,
+            Some("https://jenkins.example.com".to_owned())
+        );
+        assert_eq!(settings.github.token, Some("github-token".to_owned()));
+        assert_eq!(settings.github.token_file, None);
+    }
+
+    #[test]
+    fn settings_from_file_resolves_paths_against_its_directory() {
+        let dir = temp_dir();
+        fs::write(dir.join("github-token"), "github-token\n")
+            .expect("Failed to write temporary file");
+        fs::write(
+            dir.join("kipper.toml"),
+            r#"
+                state_file = "trackers.journal"
+
+                [github]
+                token_file = "github-token"
+            "#
+        ).expect("Failed to write temporary file");
+
+        let settings = Settings::from_file(&dir.join("kipper.toml"))
+            .expect("Failed to read settings");
+
+        assert_eq!(settings.github.token, Some("github-token".to_owned()));
+        assert_eq!(settings.state_file, Some(dir.join("trackers.journal")));
+    }
+
+    #[test]
+    fn settings_from_file_rejects_unknown_settings() {
+        let config_path = write_temp_file(
+            "unknown-settings.toml",
+            r#"
+                [jenkins]
+                uri = "https://jenkins.example.com"
+            "#
+        );
+
+        assert!(Settings::from_file(&config_path).is_err());
+    }
+
+    #[test]
+    fn settings_from_env_reads_kipper_variables() {
+        let mut vars = HashMap::new();
+        vars.insert("KIPPER_PORT", "9000");
+        vars.insert("KIPPER_JENKINS_URL", "https://ci.example.com");
+        vars.insert("KIPPER_GITHUB_WEBHOOK_SECRET", "webhook-secret");
+
+        let settings = Settings::from_env(
+            |v| vars.get(v).map(|s| s.to_string())
+        ).expect("Failed to read settings");
+
+        assert_eq!(settings.port, Some(9000));
+        assert_eq!(
+            settings.jenkins.url,
+            Some("https://ci.example.com".to_owned())
+        );
+        assert_eq!(
+            settings.github.webhook_secret,
+            Some("webhook-secret".to_owned())
+        );
+        assert_eq!(settings.github.token, None);
+    }
+
+    #[test]
+    fn read_secret_files_rejects_value_and_file_together() {
+        let token_path = write_temp_file("conflicting-token", "token");
+        let mut settings = complete_settings();
+        settings.jenkins.token_file = Some(token_path);
+
+        assert!(settings.read_secret_files().is_err());
+    }
+
+    #[test]
+    fn merge_prefers_higher_precedence_settings() {
+        let mut cli = Settings::default();
+        cli.jenkins.token = Some("cli-token".to_owned());
+        cli.port = Some(4000);
+
+        let mut env = Settings::default();
+        env.jenkins.token = Some("env-token".to_owned());
+        env.jenkins.user_id = Some("env-user".to_owned());
+
+        let settings = cli.merge(env).merge(complete_settings());
+
+        assert_eq!(settings.port, Some(4000));
+        assert_eq!(settings.jenkins.token, Some("cli-token".to_owned()));
+        assert_eq!(settings.jenkins.user_id, Some("env-user".to_owned()));
+        assert_eq!(settings.github.token, Some("github-token".to_owned()));
+    }
+
+    #[test]
+    fn config_from_settings_requires_credentials() {
+        let mut settings = complete_settings();
+        settings.github.token = None;
+
+        let error = Config::from_settings(settings)
+            .expect_err("Config should be invalid");
+
+        assert_eq!(
+            error.to_string(),
+            "missing required setting `github.token`"
+        );
+    }
+
+    #[test]
+    fn config_from_settings_requires_github_app_private_key() {
+        let mut settings = complete_settings();
+        settings.github.token = None;
+        settings.github.app_id = Some(1234);
+
+        let error = Config::from_settings(settings)
+            .expect_err("Config should be invalid");
+
+        assert_eq!(
+            error.to_string(),
+            "missing required setting `github.private_key`"
+        );
+    }
+
+    #[test]
+    fn config_to_redacted_toml_hides_secrets() {
+        let config = Config::from_settings(complete_settings())
+            .expect("Failed to build config");
+
+        let output = config.to_redacted_toml()
+            .expect("Failed to serialize config");
+
+        assert!(output.contains("url = \"https://jenkins.example.com\""));
+        assert!(output.contains("port = 8000"));
+        assert!(output.contains("api_url = \"https://api.github.com\""));
+        assert!(!output.contains("jenkins-token"));
+        assert!(!output.contains("github-token"));
+    }
+
+    #[test]
+    fn settings_from_file_parses_repo_settings() {
+        let config_path = write_temp_file(
+            "repo-settings.toml",
+            r#"
+                [repos."octocat/Hello-World"]
+                job_name_template = "PR-{number}"
+
+                [repos."octocat/Spoon-Knife"]
+                reporter = "checks"
+                job_path = "{owner}/{repo}/{branch}"
+                job_name_regex = "^{branch}-[0-9a-f]{7}$"
+            "#
+        );
+
+        let mut settings = Settings::from_file(&config_path)
+            .expect("Failed to read settings");
+        settings = complete_settings().merge(settings);
+
+        let config = Config::from_settings(settings)
+            .expect("Failed to build config");
+
+        assert_eq!(
+            config.repo("octocat", "Hello-World").job_name_template,
+            Some("PR-{number}".to_owned())
+        );
+        assert_eq!(
+            config.repo("octocat", "Spoon-Knife").job_name_regex,
+            Some("^{branch}-[0-9a-f]{7}$".to_owned())
+        );
+        assert_eq!(
+            config.repo("octocat", "Spoon-Knife").job_path,
+            Some("{owner}/{repo}/{branch}".to_owned())
+        );
+        assert_eq!(
+            config.repo("octocat", "Spoon-Knife").reporter,
+            ReporterKind::Checks
+        );
+        assert_eq!(config.repo("octocat", "linguist").job_name_template, None);
+        assert_eq!(
+            config.repo("octocat", "linguist").reporter,
+            ReporterKind::Status
+        );
+    }
+
+    #[test]
+    fn config_from_settings_rejects_invalid_job_name_template() {
+        let mut settings = complete_settings();
+        settings.repos.insert(
+            "octocat/Hello-World".to_owned(),
+            RepoSettings {
+                reporter: None,
+                job_path: None,
+                job_name_template: Some("{branch}-{commit}".to_owned()),
+                job_name_regex: None,
+                ..RepoSettings::default()
+            },
+        );
+
+        let error = Config::from_settings(settings)
+            .expect_err("Config should be invalid");
+
+        assert!(error.to_string().starts_with("repos.\"octocat/Hello-World\""));
+    }
+
+    #[test]
+    fn config_from_settings_rejects_template_and_regex_together() {
+        let mut settings = complete_settings();
+        settings.repos.insert(
+            "octocat/Hello-World".to_owned(),
+            RepoSettings {
+                reporter: None,
+                job_path: None,
+                job_name_template: Some("PR-{number}".to_owned()),
+                job_name_regex: Some("^PR-{number}$".to_owned()),
+                ..RepoSettings::default()
+            },
+        );
+
+        assert!(Config::from_settings(settings).is_err());
+    }
+
+    #[test]
+    fn repo_config_reads_timing_settings() {
+        let config_path = write_temp_file(
+            "repo-timing.toml",
+            r#"
+                [repos."octocat/Hello-World"]
+                poll_interval = 60
+                warm_up = 120
+                discovery_timeout = 900
+                timeout = 5400
+                timeout_factor = 1.5
+            "#
+        );
+
+        let settings = complete_settings().merge(
+            Settings::from_file(&config_path).expect("Failed to read settings")
+        );
+        let config = Config::from_settings(settings)
+            .expect("Failed to build config");
+
+        let repo = config.repo("octocat", "Hello-World");
+        assert_eq!(repo.poll_interval, 60);
+        assert_eq!(repo.warm_up, 120);
+        assert_eq!(repo.discovery_timeout, 900);
+        assert_eq!(repo.timeout, 5400);
+
+        let default = config.repo("octocat", "linguist");
+        assert_eq!(default.poll_interval, 30);
+        assert_eq!(default.warm_up, 30);
+        assert_eq!(default.discovery_timeout, 10 * 60);
+        assert_eq!(default.timeout, 20 * 60);
+    }
+
+    #[test]
+    fn repo_config_timeout_follows_estimated_duration() {
+        let mut job = Job {
+            display_name: "new-topic-6dcb0".to_owned(),
+            result: JobStatus::Pending,
+            ..Job::default()
+        };
+
+        let mut repo = RepoConfig {
+            timeout: 5400,
+            ..RepoConfig::default()
+        };
+        assert_eq!(repo.timeout_for(&job), 5400);
+
+        repo.timeout_factor = Some(1.5);
+        assert_eq!(repo.timeout_for(&job), 5400);
+
+        // Jenkins expects the build to take an hour.
+        job.estimated_duration = 60 * 60 * 1000;
+        assert_eq!(repo.timeout_for(&job), 90 * 60);
+    }
+
+    #[test]
+    fn config_from_settings_rejects_zero_poll_interval() {
+        let mut settings = complete_settings();
+        settings.repos.insert(
+            "octocat/Hello-World".to_owned(),
+            RepoSettings {
+                poll_interval: Some(0),
+                ..RepoSettings::default()
+            },
+        );
+
+        assert!(Config::from_settings(settings).is_err());
+    }
+
+    #[test]
+    fn repo_config_maps_build_results() {
+        let config_path = write_temp_file(
+            "repo-results.toml",
+            r#"
+                [repos."octocat/Hello-World".results]
+                UNSTABLE = "success"
+                NOT_BUILT = "failure"
+            "#
+        );
+
+        let settings = complete_settings().merge(
+            Settings::from_file(&config_path).expect("Failed to read settings")
+        );
+        let config = Config::from_settings(settings)
+            .expect("Failed to build config");
+
+        let repo = config.repo("octocat", "Hello-World");
+        assert_eq!(repo.commit_status(&JobStatus::Unstable), CommitStatus::Success);
+        assert_eq!(repo.commit_status(&JobStatus::NotBuilt), CommitStatus::Failure);
+        assert_eq!(repo.commit_status(&JobStatus::Aborted), CommitStatus::Error);
+        assert_eq!(repo.commit_status(&JobStatus::Pending), CommitStatus::Pending);
+
+        let default = config.repo("octocat", "linguist");
+        assert_eq!(default.commit_status(&JobStatus::Unstable), CommitStatus::Failure);
+    }
+
+    #[test]
+    fn config_from_settings_rejects_invalid_result_mappings() {
+        for &(result, status) in &[("UNSTABLE", "pending"), ("FLAKY", "success")] {
+            let mut settings = complete_settings();
+            let mut results = BTreeMap::new();
+            results.insert(result.to_owned(), status.to_owned());
+
+            settings.repos.insert(
+                "octocat/Hello-World".to_owned(),
+                RepoSettings {
+                    results,
+                    ..RepoSettings::default()
+                },
+            );
+
+            assert!(Config::from_settings(settings).is_err());
+        }
+    }
+
+    #[test]
+    fn repo_config_reads_pull_request_policies() {
+        let config_path = write_temp_file(
+            "repo-forks.toml",
+            r#"
+                [repos."octocat/Hello-World"]
+                untrusted_forks = "label"
+                fork_label = "safe to test"
+
+                [repos."octocat/Spoon-Knife"]
+                untrusted_forks = "skip"
+                skip_drafts = true
+                run_labels = ["ci:run"]
+                track_tags = true
+            "#
+        );
+
+        let settings = complete_settings().merge(
+            Settings::from_file(&config_path).expect("Failed to read settings")
+        );
+        let config = Config::from_settings(settings)
+            .expect("Failed to build config");
+
+        let repo = config.repo("octocat", "Hello-World");
+        assert_eq!(repo.untrusted_forks, ForkPolicy::Label);
+        assert_eq!(repo.fork_label, Some("safe to test".to_owned()));
+
+        let spoon_knife = config.repo("octocat", "Spoon-Knife");
+        assert_eq!(spoon_knife.untrusted_forks, ForkPolicy::Skip);
+        assert!(spoon_knife.skip_drafts);
+        assert_eq!(spoon_knife.run_labels, vec!["ci:run".to_owned()]);
+        assert!(spoon_knife.track_tags);
+        assert!(!repo.skip_drafts);
+        assert_eq!(
+            config.repo("octocat", "linguist").untrusted_forks,
+            ForkPolicy::Track
+        );
+    }
+
+    #[test]
+    fn config_from_settings_requires_fork_label_with_label_policy() {
+        let mut settings = complete_settings();
+        settings.repos.insert(
+            "octocat/Hello-World".to_owned(),
+            RepoSettings {
+                untrusted_forks: Some("label".to_owned()),
+                ..RepoSettings::default()
+            },
+        );
+
+        assert!(Config::from_settings(settings).is_err());
+    }
+
+    #[test]
+    fn config_from_settings_requires_gitlab_url_with_token() {
+        let mut settings = complete_settings();
+        settings.gitlab.token = Some("gitlab-token".to_owned());
+
+        let error = Config::from_settings(settings)
+            .expect_err("Config should be invalid");
+
+        assert_eq!(error.to_string(), "missing required setting `gitlab.url`");
+
+        let config = Config::from_settings(complete_settings())
+            .expect("Failed to build config");
+
+        assert!(config.gitlab.is_none());
+    }
+}
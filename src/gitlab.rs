@@ -0,0 +1,264 @@
+// Copyright © 2017 Teddy Wing
+//
+// This file is part of Kipper.
+//
+// Kipper is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Kipper is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kipper. If not, see <http://www.gnu.org/licenses/>.
+
+extern crate json;
+extern crate openssl;
+extern crate reqwest;
+extern crate url;
+
+use std::collections::HashMap;
+use std::error::Error;
+
+use self::openssl::memcmp;
+use self::reqwest::header::Headers;
+use self::url::percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+
+use github::CommitStatus;
+use http::{self, RetryPolicy};
+use pull_request::{CommitRef, Forge};
+
+// Build a commit reference from a GitLab Merge Request Hook payload. The
+// project's namespace stands in for GitHub's owner, so nested groups give
+// owners like "group/subgroup".
+pub fn commit_ref(
+    merge_request_event: &json::JsonValue
+) -> Result<CommitRef, Box<dyn Error>> {
+    let attributes = &merge_request_event["object_attributes"];
+
+    let path = attributes["source"]["path_with_namespace"].as_str()
+        .ok_or("merge request event has no `object_attributes.source.path_with_namespace`")?;
+    let (owner, repo) = match path.rfind('/') {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => return Err(format!("invalid GitLab project path '{}'", path).into()),
+    };
+
+    let sha = attributes["last_commit"]["id"].as_str()
+        .ok_or("merge request event has no `object_attributes.last_commit.id`")?;
+    let branch = attributes["source_branch"].as_str()
+        .ok_or("merge request event has no `object_attributes.source_branch`")?;
+
+    Ok(
+        CommitRef {
+            owner: owner.to_owned(),
+            repo: repo.to_owned(),
+            head_repo: None,
+            sha: sha.to_owned(),
+            branch: branch.to_owned(),
+            base_branch: attributes["target_branch"].as_str().map(str::to_owned),
+            number: attributes["iid"].as_u64(),
+            author: merge_request_event["user"]["username"].as_str()
+                .map(str::to_owned),
+            title: attributes["title"].as_str().map(str::to_owned),
+            labels: merge_request_event["labels"].members()
+                .filter_map(|label| label["title"].as_str())
+                .map(str::to_owned)
+                .collect(),
+            forge: Forge::GitLab,
+            installation_id: None,
+        }
+    )
+}
+
+pub fn merge_request_closed(merge_request_event: &json::JsonValue) -> bool {
+    let action = &merge_request_event["object_attributes"]["action"];
+
+    action == "close" || action == "merge"
+}
+
+// Opening a merge request and pushing to it need a build. Other updates,
+// like changing the title, come without `oldrev`.
+pub fn merge_request_needs_build(merge_request_event: &json::JsonValue) -> bool {
+    let attributes = &merge_request_event["object_attributes"];
+
+    match attributes["action"].as_str() {
+        Some("open") | Some("reopen") => true,
+        Some("update") => !attributes["oldrev"].is_null(),
+        _ => false,
+    }
+}
+
+// GitLab sends the webhook's secret token as is in `X-Gitlab-Token`.
+pub fn webhook_token_is_valid(secret: &str, token: &str) -> bool {
+    secret.len() == token.len()
+        && memcmp::eq(secret.as_bytes(), token.as_bytes())
+}
+
+pub fn update_commit_status(
+    gitlab_url: &str,
+    gitlab_token: &str,
+    commit_ref: &CommitRef,
+    state: &CommitStatus,
+    target_url: Option<String>,
+    description: Option<String>,
+    name: String,
+) -> Result<(), Box<dyn Error>> {
+    let client = http::Client::new(reqwest::Client::new());
+
+    let mut params = HashMap::new();
+    params.insert("state", gitlab_state(state, target_url.is_some()).to_owned());
+    params.insert("ref", commit_ref.branch.clone());
+    params.insert("name", name);
+
+    if let Some(u) = target_url {
+        params.insert("target_url", u);
+    }
+
+    if let Some(d) = description {
+        params.insert("description", d);
+    }
+
+    // Projects can be referred to by their URL-encoded path instead of their
+    // numeric ID.
+    let project = format!("{}/{}", commit_ref.owner, commit_ref.repo);
+
+    let mut headers = Headers::new();
+    headers.set_raw("PRIVATE-TOKEN", gitlab_token.to_owned());
+
+    let url = format!(
+        "{}/api/v4/projects/{}/statuses/{}",
+        gitlab_url,
+        utf8_percent_encode(&project, PATH_SEGMENT_ENCODE_SET),
+        commit_ref.sha
+    );
+
+    http::send(&client, &RetryPolicy::repeatable(), || {
+        client.post(&url)
+            .headers(headers.clone())
+            .json(&params)
+            .build()
+    }).map_err(|e| format!("GitLab status update failed: {}", e))?;
+
+    Ok(())
+}
+
+// GitLab distinguishes between builds waiting to start and running ones.
+fn gitlab_state(state: &CommitStatus, has_build: bool) -> &'static str {
+    match *state {
+        CommitStatus::Pending if has_build => "running",
+        CommitStatus::Pending => "pending",
+        CommitStatus::Success => "success",
+        CommitStatus::Failure => "failed",
+        CommitStatus::Error => "canceled",
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate mockito;
+
+    use self::mockito::{mock, Matcher};
+
+    use super::*;
+
+    const PAYLOAD: &str = r#"{
+      "object_kind": "merge_request",
+      "event_type": "merge_request",
+      "user": { "name": "Administrator", "username": "root" },
+      "project": {
+        "id": 1,
+        "name": "Gitlab Test",
+        "path_with_namespace": "gitlabhq/gitlab-test"
+      },
+      "object_attributes": {
+        "id": 99,
+        "iid": 1,
+        "target_branch": "master",
+        "source_branch": "ms-viewport",
+        "source_project_id": 14,
+        "target_project_id": 14,
+        "title": "MS-Viewport",
+        "state": "opened",
+        "source": {
+          "name": "Awesome Project",
+          "path_with_namespace": "awesome_space/awesome_project"
+        },
+        "last_commit": {
+          "id": "da1560886d4f094c3e6c9ef40349f7d38b5d27d7",
+          "message": "fixed readme"
+        },
+        "oldrev": "a53e6d2d4c5b8b1c3e5e7e0b2c9f0d1e2a3b4c5d",
+        "action": "update"
+      }
+    }"#;
+
+    #[test]
+    fn commit_ref_reads_merge_request_event() {
+        let event = json::parse(PAYLOAD).expect("Failed to parse payload");
+
+        let commit_ref = commit_ref(&event).expect("Failed to read commit");
+
+        assert_eq!(commit_ref.owner, "awesome_space");
+        assert_eq!(commit_ref.repo, "awesome_project");
+        assert_eq!(commit_ref.sha, "da1560886d4f094c3e6c9ef40349f7d38b5d27d7");
+        assert_eq!(commit_ref.branch, "ms-viewport");
+        assert_eq!(commit_ref.base_branch, Some("master".to_owned()));
+        assert_eq!(commit_ref.number, Some(1));
+        assert_eq!(commit_ref.author, Some("root".to_owned()));
+        assert_eq!(commit_ref.title, Some("MS-Viewport".to_owned()));
+        assert_eq!(commit_ref.forge, Forge::GitLab);
+    }
+
+    #[test]
+    fn merge_request_needs_build_for_new_commits_only() {
+        let mut event = json::parse(PAYLOAD).expect("Failed to parse payload");
+        assert!(merge_request_needs_build(&event));
+
+        event["object_attributes"].remove("oldrev");
+        assert!(!merge_request_needs_build(&event));
+
+        event["object_attributes"]["action"] = "open".into();
+        assert!(merge_request_needs_build(&event));
+
+        event["object_attributes"]["action"] = "merge".into();
+        assert!(!merge_request_needs_build(&event));
+        assert!(merge_request_closed(&event));
+    }
+
+    #[test]
+    fn webhook_token_is_valid_compares_tokens() {
+        assert!(webhook_token_is_valid("s3cret", "s3cret"));
+        assert!(!webhook_token_is_valid("s3cret", "s3cre"));
+        assert!(!webhook_token_is_valid("s3cret", "secret"));
+    }
+
+    #[test]
+    fn update_commit_status_makes_a_request_to_gitlab() {
+        let mock = mock(
+                "POST",
+                "/api/v4/projects/awesome_space%2Fawesome_project/statuses/da1560886d4f094c3e6c9ef40349f7d38b5d27d7"
+            )
+            .match_header("PRIVATE-TOKEN", "gitlab-token")
+            .match_body(Matcher::Regex(r#""state":"running""#.to_owned()))
+            .with_status(201)
+            .create();
+
+        let event = json::parse(PAYLOAD).expect("Failed to parse payload");
+
+        update_commit_status(
+            mockito::SERVER_URL,
+            "gitlab-token",
+            &commit_ref(&event).expect("Failed to read commit"),
+            &CommitStatus::Pending,
+            Some("https://jenkins.example.com/job/awesome_project-branches/3/console".to_owned()),
+            None,
+            "continuous-integration/jenkins".to_owned()
+        ).expect("Failed to update commit status");
+
+        mock.assert();
+    }
+}
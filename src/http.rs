@@ -0,0 +1,683 @@
+// Copyright © 2017 Teddy Wing
+//
+// This file is part of Kipper.
+//
+// Kipper is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Kipper is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kipper. If not, see <http://www.gnu.org/licenses/>.
+
+extern crate reqwest;
+extern crate url;
+
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::error::Error;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::ops::Deref;
+use std::str;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use self::reqwest::{Method, Request, Response, StatusCode};
+use self::url::Url;
+
+// How many times to try a request, and how long to wait in between.
+// Failures back off exponentially, with jitter so that trackers polling the
+// same server don't retry in lockstep.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+
+    // Also the longest wait a `Retry-After` or `X-RateLimit-Reset` header can
+    // ask for. Rather than wait longer, the request fails. Waits hold up a
+    // worker thread, so they're kept well below the poll interval.
+    pub max_delay: Duration,
+
+    // Whether to retry requests like POST, which may have taken effect even
+    // though they failed. Otherwise they're only retried if they couldn't be
+    // sent at all.
+    pub retry_non_idempotent: bool,
+}
+
+impl RetryPolicy {
+    // For requests that aren't idempotent but are harmless to repeat, like
+    // posting a commit status.
+    pub fn repeatable() -> RetryPolicy {
+        RetryPolicy {
+            retry_non_idempotent: true,
+            ..RetryPolicy::default()
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 4,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            retry_non_idempotent: false,
+        }
+    }
+}
+
+// A response with a non-2xx status.
+#[derive(Debug)]
+pub struct HttpError {
+    pub url: String,
+    pub status: StatusCode,
+    pub body: String,
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.url, self.status, self.body)
+    }
+}
+
+impl Error for HttpError {}
+
+// A client whose requests wait for a slot from a `HostLimiter`, if it has
+// one. The slot is only held while a request is being made, not while waiting
+// to retry it.
+#[derive(Clone)]
+pub struct Client<'a> {
+    client: reqwest::Client,
+    limiter: Option<&'a HostLimiter>,
+}
+
+impl Client<'static> {
+    pub fn new(client: reqwest::Client) -> Client<'static> {
+        Client {
+            client,
+            limiter: None,
+        }
+    }
+}
+
+impl<'a> Client<'a> {
+    pub fn limited_by(self, limiter: &HostLimiter) -> Client<'_> {
+        Client {
+            client: self.client,
+            limiter: Some(limiter),
+        }
+    }
+}
+
+impl<'a> Deref for Client<'a> {
+    type Target = reqwest::Client;
+
+    fn deref(&self) -> &reqwest::Client {
+        &self.client
+    }
+}
+
+// Send a request with `client`, retrying connection errors, server errors
+// and rate limits. `request` builds the request for each attempt, as requests
+// can only be sent once. Any non-2xx response that's left is returned as an
+// `HttpError`.
+pub fn send<F>(
+    client: &Client,
+    policy: &RetryPolicy,
+    request: F,
+) -> Result<Response, Box<dyn Error>>
+where F: FnMut() -> reqwest::Result<Request> {
+    send_request(client, policy, request, thread::sleep)
+}
+
+fn send_request<F, W>(
+    client: &Client,
+    policy: &RetryPolicy,
+    mut request: F,
+    wait: W,
+) -> Result<Response, Box<dyn Error>>
+where
+    F: FnMut() -> reqwest::Result<Request>,
+    W: FnMut(Duration),
+{
+    let first = request()?;
+    let repeatable = policy.retry_non_idempotent || is_idempotent(first.method());
+    let mut first = Some(first);
+
+    send_with(
+        policy,
+        repeatable,
+        || {
+            let request = match first.take() {
+                Some(r) => r,
+                None => request()?,
+            };
+
+            let _permit = client.limiter
+                .map(|limiter| limiter.acquire(request.url().as_str()));
+
+            client.execute(request)
+        },
+        wait
+    )
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::Get | Method::Head | Method::Put | Method::Delete
+            | Method::Options | Method::Trace
+    )
+}
+
+// Whether a request failed before the server could have received it: the
+// connection was refused, or had already been closed by the server, as
+// happens to kept-alive connections.
+fn was_not_sent(error: &reqwest::Error) -> bool {
+    error.get_ref()
+        .and_then(|e| e.downcast_ref::<io::Error>())
+        .is_some_and(|e| {
+            matches!(
+                e.kind(),
+                io::ErrorKind::ConnectionRefused | io::ErrorKind::BrokenPipe
+            )
+        })
+}
+
+fn send_with<F, W>(
+    policy: &RetryPolicy,
+    repeatable: bool,
+    mut request: F,
+    mut wait: W,
+) -> Result<Response, Box<dyn Error>>
+where
+    F: FnMut() -> reqwest::Result<Response>,
+    W: FnMut(Duration),
+{
+    let mut attempt = 1;
+
+    loop {
+        let (error, delay): (Box<dyn Error>, Option<Duration>) = match request() {
+            Ok(mut response) => {
+                if response.status().is_success() {
+                    if attempt > 1 {
+                        info!(
+                            "{}: succeeded after {} retries",
+                            response.url(),
+                            attempt - 1
+                        );
+                    }
+
+                    return Ok(response)
+                }
+
+                let delay = if repeatable {
+                    response_delay(&response, policy, attempt)
+                } else {
+                    None
+                };
+
+                let error = HttpError {
+                    url: response.url().to_string(),
+                    status: response.status(),
+                    body: response.text().unwrap_or_default(),
+                };
+
+                (error.into(), delay)
+            },
+            Err(e) => {
+                let delay = if repeatable || was_not_sent(&e) {
+                    Some(backoff(policy, attempt))
+                } else {
+                    None
+                };
+
+                (e.into(), delay)
+            },
+        };
+
+        let delay = match delay {
+            Some(d) if attempt < policy.max_attempts && d <= policy.max_delay => d,
+            _ => {
+                if attempt > 1 {
+                    warn!("{}: giving up after {} attempts", error, attempt);
+                }
+
+                return Err(error)
+            },
+        };
+
+        warn!(
+            "{}: attempt {} of {} failed, retrying in {}ms",
+            error,
+            attempt,
+            policy.max_attempts,
+            delay.as_millis()
+        );
+
+        wait(delay);
+        attempt += 1;
+    }
+}
+
+// How long to wait before retrying a failed response, or `None` if it
+// shouldn't be retried.
+fn response_delay(
+    response: &Response,
+    policy: &RetryPolicy,
+    attempt: u32,
+) -> Option<Duration> {
+    let retry_after = header_u64(response, "Retry-After")
+        .map(Duration::from_secs);
+
+    // GitHub's primary rate limit says when it resets rather than how long to
+    // wait.
+    let rate_limit_reset = if header_u64(response, "X-RateLimit-Remaining") == Some(0) {
+        header_u64(response, "X-RateLimit-Reset")
+            .map(|reset| Duration::from_secs(reset.saturating_sub(unix_time())))
+    } else {
+        None
+    };
+
+    match response.status().as_u16() {
+        // Secondary rate limits come as a 403 with `Retry-After`.
+        403 | 429 => retry_after
+            .or(rate_limit_reset)
+            .or_else(|| {
+                if response.status().as_u16() == 429 {
+                    Some(backoff(policy, attempt))
+                } else {
+                    None
+                }
+            }),
+        408 | 500 | 502 | 503 | 504 => retry_after
+            .or_else(|| Some(backoff(policy, attempt))),
+        _ => None,
+    }
+}
+
+// Exponential backoff with "equal jitter": somewhere between half and all of
+// `base_delay * 2^(attempt - 1)`.
+fn backoff(policy: &RetryPolicy, attempt: u32) -> Duration {
+    let delay = policy.base_delay
+        .checked_mul(1 << (attempt - 1).min(16))
+        .unwrap_or(policy.max_delay)
+        .min(policy.max_delay);
+
+    let half = delay / 2;
+    let jitter_range = (delay - half).as_millis() as u64 + 1;
+
+    half + Duration::from_millis(random() % jitter_range)
+}
+
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+// Caps the number of requests in flight to each host, so a burst of trackers
+// doesn't overwhelm a Jenkins server.
+pub struct HostLimiter {
+    limit: usize,
+    active: Mutex<HashMap<String, usize>>,
+    condvar: Condvar,
+}
+
+impl HostLimiter {
+    pub fn new(limit: usize) -> HostLimiter {
+        HostLimiter {
+            limit,
+            active: Mutex::new(HashMap::new()),
+            condvar: Condvar::new(),
+        }
+    }
+
+    // Wait until a request can be made to the host of `url`. The slot is
+    // held until the returned `Permit` is dropped.
+    pub fn acquire(&self, url: &str) -> Permit<'_> {
+        let host = host(url);
+
+        let mut active = self.active.lock()
+            .unwrap_or_else(|e| e.into_inner());
+
+        while active.get(&host).cloned().unwrap_or(0) >= self.limit {
+            active = self.condvar.wait(active)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+
+        *active.entry(host.clone()).or_insert(0) += 1;
+
+        Permit {
+            limiter: self,
+            host,
+        }
+    }
+}
+
+pub struct Permit<'a> {
+    limiter: &'a HostLimiter,
+    host: String,
+}
+
+impl<'a> Drop for Permit<'a> {
+    fn drop(&mut self) {
+        let mut active = self.limiter.active.lock()
+            .unwrap_or_else(|e| e.into_inner());
+
+        if let Some(count) = active.get_mut(&self.host) {
+            *count -= 1;
+        }
+
+        self.limiter.condvar.notify_all();
+    }
+}
+
+fn host(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(url) => format!(
+            "{}:{}",
+            url.host_str().unwrap_or_default(),
+            url.port_or_known_default().unwrap_or(0)
+        ),
+        Err(_) => url.to_owned(),
+    }
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn header_u64(response: &Response, name: &str) -> Option<u64> {
+    response.headers().get_raw(name)
+        .and_then(|raw| raw.one())
+        .and_then(|value| str::from_utf8(value).ok())
+        .and_then(|value| value.trim().parse().ok())
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate mockito;
+
+    use self::mockito::mock;
+
+    use super::*;
+
+    fn test_policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(60),
+            retry_non_idempotent: false,
+        }
+    }
+
+    fn get(path: &str) -> reqwest::Result<Response> {
+        reqwest::Client::new()
+            .get(&format!("{}{}", mockito::SERVER_URL, path))
+            .send()
+    }
+
+    #[test]
+    fn send_retries_server_errors_until_success() {
+        let _failure = mock("GET", "/http/retries-server-errors")
+            .with_status(502)
+            .with_header("connection", "close")
+            .create();
+        let mut success = None;
+
+        let mut delays = Vec::new();
+        let mut response = send_with(
+            &test_policy(),
+            true,
+            || get("/http/retries-server-errors"),
+            |delay| {
+                delays.push(delay);
+
+                // Jenkins is back after the first failure.
+                success = Some(
+                    mock("GET", "/http/retries-server-errors")
+                        .with_status(200)
+                        .with_body("ok")
+                        .create()
+                );
+            }
+        ).expect("Request should succeed after retrying");
+
+        assert_eq!(response.text().expect("Failed to read body"), "ok");
+        assert_eq!(delays.len(), 1);
+        assert!(delays[0] >= Duration::from_millis(50));
+        assert!(delays[0] <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn send_gives_up_after_max_attempts() {
+        let _failure = mock("GET", "/http/gives-up")
+            .with_status(503)
+            .with_header("connection", "close")
+            .with_body("Jenkins is restarting")
+            .create();
+
+        let mut delays = Vec::new();
+        let error = send_with(
+            &test_policy(),
+            true,
+            || get("/http/gives-up"),
+            |delay| delays.push(delay)
+        ).expect_err("Request should fail");
+
+        assert_eq!(delays.len(), 2);
+        assert!(delays[1] >= Duration::from_millis(100));
+
+        let error = error.downcast_ref::<HttpError>()
+            .expect("Error should be an HttpError");
+        assert_eq!(error.status, StatusCode::ServiceUnavailable);
+        assert_eq!(error.body, "Jenkins is restarting");
+    }
+
+    #[test]
+    fn send_honors_retry_after() {
+        let _failure = mock("GET", "/http/retry-after")
+            .with_status(403)
+            .with_header("connection", "close")
+            .with_header("Retry-After", "30")
+            .with_body(r#"{"message": "You have exceeded a secondary rate limit."}"#)
+            .create();
+        let mut success = None;
+
+        let mut delays = Vec::new();
+        send_with(
+            &test_policy(),
+            true,
+            || get("/http/retry-after"),
+            |delay| {
+                delays.push(delay);
+                success = Some(
+                    mock("GET", "/http/retry-after")
+                        .with_status(200)
+                        .create()
+                );
+            }
+        ).expect("Request should succeed after retrying");
+
+        assert_eq!(delays, vec![Duration::from_secs(30)]);
+    }
+
+    #[test]
+    fn send_waits_for_rate_limit_reset() {
+        let reset = unix_time() + 20;
+
+        let _failure = mock("GET", "/http/rate-limit-reset")
+            .with_status(403)
+            .with_header("connection", "close")
+            .with_header("X-RateLimit-Remaining", "0")
+            .with_header("X-RateLimit-Reset", &reset.to_string())
+            .create();
+        let mut success = None;
+
+        let mut delays = Vec::new();
+        send_with(
+            &test_policy(),
+            true,
+            || get("/http/rate-limit-reset"),
+            |delay| {
+                delays.push(delay);
+                success = Some(
+                    mock("GET", "/http/rate-limit-reset")
+                        .with_status(200)
+                        .create()
+                );
+            }
+        ).expect("Request should succeed after retrying");
+
+        assert_eq!(delays.len(), 1);
+        assert!(delays[0] <= Duration::from_secs(20));
+        assert!(delays[0] >= Duration::from_secs(18));
+    }
+
+    #[test]
+    fn send_does_not_retry_client_errors() {
+        let _not_found = mock("GET", "/http/client-error")
+            .with_status(404)
+            .with_header("connection", "close")
+            .create();
+
+        let mut delays = Vec::new();
+        let error = send_with(
+            &test_policy(),
+            true,
+            || get("/http/client-error"),
+            |delay| delays.push(delay)
+        ).expect_err("Request should fail");
+
+        assert!(delays.is_empty());
+        assert_eq!(
+            error.downcast_ref::<HttpError>().map(|e| e.status),
+            Some(StatusCode::NotFound)
+        );
+    }
+
+    #[test]
+    fn host_limiter_blocks_requests_over_the_limit() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let limiter = Arc::new(HostLimiter::new(1));
+        let acquired = Arc::new(AtomicBool::new(false));
+
+        let permit = limiter.acquire("http://jenkins.example.com/job/a/");
+
+        // Other hosts aren't affected.
+        drop(limiter.acquire("http://ci.example.com/job/a/"));
+
+        let waiting = {
+            let limiter = limiter.clone();
+            let acquired = acquired.clone();
+
+            thread::spawn(move || {
+                let _permit = limiter.acquire("http://jenkins.example.com/job/b/");
+                acquired.store(true, Ordering::SeqCst);
+            })
+        };
+
+        thread::sleep(Duration::from_millis(50));
+        assert!(!acquired.load(Ordering::SeqCst));
+
+        drop(permit);
+        waiting.join().expect("Waiting thread panicked");
+
+        assert!(acquired.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn send_does_not_wait_longer_than_max_delay() {
+        let _failure = mock("GET", "/http/long-retry-after")
+            .with_status(429)
+            .with_header("connection", "close")
+            .with_header("Retry-After", "3600")
+            .create();
+
+        let mut delays = Vec::new();
+        assert!(
+            send_with(
+                &test_policy(),
+                true,
+                || get("/http/long-retry-after"),
+                |delay| delays.push(delay)
+            ).is_err()
+        );
+        assert!(delays.is_empty());
+    }
+
+    #[test]
+    fn send_does_not_retry_post_by_default() {
+        let failure = mock("POST", "/http/post-server-error")
+            .with_status(502)
+            .with_header("connection", "close")
+            .expect(1)
+            .create();
+
+        let client = Client::new(reqwest::Client::new());
+        let url = format!("{}/http/post-server-error", mockito::SERVER_URL);
+
+        let error = send(&client, &RetryPolicy::default(), || {
+            client.post(&url).build()
+        }).expect_err("Request should fail");
+
+        assert_eq!(
+            error.downcast_ref::<HttpError>().map(|e| e.status),
+            Some(StatusCode::BadGateway)
+        );
+        failure.assert();
+    }
+
+    #[test]
+    fn send_retries_unsent_post() {
+        let mut delays = Vec::new();
+        assert!(
+            send_with(
+                &test_policy(),
+                false,
+                || reqwest::Client::new().post("http://127.0.0.1:1/").send(),
+                |delay| delays.push(delay)
+            ).is_err()
+        );
+
+        // Nothing listens on port 1, so the request is refused each time.
+        assert_eq!(delays.len(), 2);
+    }
+
+    #[test]
+    fn send_releases_host_limiter_while_waiting_to_retry() {
+        let _failure = mock("GET", "/http/limited-retry")
+            .with_status(503)
+            .with_header("connection", "close")
+            .create();
+
+        let limiter = HostLimiter::new(1);
+        let client = Client::new(reqwest::Client::new()).limited_by(&limiter);
+        let url = format!("{}/http/limited-retry", mockito::SERVER_URL);
+
+        let mut held = Vec::new();
+        assert!(
+            send_request(
+                &client,
+                &test_policy(),
+                || client.get(&url).build(),
+                |_| held.push(limiter.active.lock().unwrap().values().sum::<usize>())
+            ).is_err()
+        );
+
+        assert_eq!(held, vec![0, 0]);
+    }
+}
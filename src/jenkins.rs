@@ -31,7 +31,7 @@
 //   set GH status to error (no job found)
 
 // fn update_github_status(commit_ref)
-// fn get_jobs(repo_name)
+// fn get_jobs(job_path)
 // fn af83 job name from commit_ref (separate af83 module)
 // fn update_github_commit_status(status, message) (lives in GitHub module)
 // fn request_job(url)
@@ -43,179 +43,249 @@ extern crate reqwest;
 extern crate url;
 
 use std::error::Error;
-use std::thread::sleep;
-use std::time::{Duration, Instant};
+use std::str::FromStr;
 
 use self::reqwest::header;
 use self::url::Url;
 
-use af83;
 use github;
+use http::{self, HttpError, RetryPolicy};
+use job_matcher::JobMatcher;
 use pull_request::CommitRef;
 
-#[derive(Debug, PartialEq, Eq)]
+// The build fields requested when listing a job's builds.
+const BUILD_TREE: &str =
+    "number,url,displayName,result,building,timestamp,\
+     actions[lastBuiltRevision[SHA1],parameters[name,value]]";
+
+// The queue item fields requested when looking for a commit's build.
+const QUEUE_TREE: &str =
+    "items[url,why,task[url],actions[parameters[name,value]]]";
+
+// Build parameters that hold the commit being built.
+const REVISION_PARAMETERS: [&str; 2] = ["GIT_COMMIT", "sha1"];
+
+#[derive(Debug, Default, PartialEq, Eq)]
 pub enum JobStatus {
     Success,
+
+    // The build ran, but some tests failed.
+    Unstable,
+
     Failure,
+    NotBuilt,
+    Aborted,
+
+    // The build is still running.
+    #[default]
     Pending,
+
     Unknown,
 }
 
 impl JobStatus {
-    fn commit_status(&self) -> github::CommitStatus {
+    // The status to post for a build when the repository doesn't say
+    // otherwise.
+    pub fn commit_status(&self) -> github::CommitStatus {
         match *self {
             JobStatus::Success => github::CommitStatus::Success,
-            JobStatus::Failure => github::CommitStatus::Failure,
+            JobStatus::Unstable | JobStatus::Failure => github::CommitStatus::Failure,
             JobStatus::Pending => github::CommitStatus::Pending,
-            JobStatus::Unknown => github::CommitStatus::Error,
+            JobStatus::NotBuilt | JobStatus::Aborted | JobStatus::Unknown => {
+                github::CommitStatus::Error
+            },
+        }
+    }
+
+    // The Jenkins name of a finished build's result.
+    pub fn name(&self) -> Option<&'static str> {
+        match *self {
+            JobStatus::Success => Some("SUCCESS"),
+            JobStatus::Unstable => Some("UNSTABLE"),
+            JobStatus::Failure => Some("FAILURE"),
+            JobStatus::NotBuilt => Some("NOT_BUILT"),
+            JobStatus::Aborted => Some("ABORTED"),
+            JobStatus::Pending | JobStatus::Unknown => None,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match *self {
+            JobStatus::Success => "Build succeeded",
+            JobStatus::Unstable => "Build unstable, some tests failed",
+            JobStatus::Failure => "Build failed",
+            JobStatus::NotBuilt => "Build not run",
+            JobStatus::Aborted => "Build aborted",
+            JobStatus::Pending => "Build in progress",
+            JobStatus::Unknown => "Build finished with an unknown result",
+        }
+    }
+}
+
+impl FromStr for JobStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<JobStatus, String> {
+        match s {
+            "SUCCESS" => Ok(JobStatus::Success),
+            "UNSTABLE" => Ok(JobStatus::Unstable),
+            "FAILURE" => Ok(JobStatus::Failure),
+            "NOT_BUILT" => Ok(JobStatus::NotBuilt),
+            "ABORTED" => Ok(JobStatus::Aborted),
+            _ => Err(format!("unknown build result '{}'", s)),
         }
     }
 }
 
+#[derive(Default)]
 pub struct Job {
-    display_name: String,
-    result: JobStatus,
+    pub number: u64,
+    pub url: String,
+    pub display_name: String,
+    pub result: JobStatus,
+    pub building: bool,
+
+    // When the build was scheduled, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+
+    // The commits the build was run for, from the Git plugin's build data
+    // and from `GIT_COMMIT` or `sha1` parameters. Pipelines that load shared
+    // libraries record a revision for each library too.
+    pub revisions: Vec<String>,
+
+    // Why an aborted build was stopped, e.g. "Aborted by jdoe".
+    pub interruption: Option<String>,
+
+    // In milliseconds. Zero until the build has finished.
+    pub duration: u64,
+
+    // In milliseconds, from the durations of previous builds. Zero if Jenkins
+    // has no estimate.
+    pub estimated_duration: u64,
 }
 
 impl Job {
-    fn new(payload: String) -> Result<Job, Box<Error>> {
-        let mut job = json::parse(payload.as_ref())?;
-
-        Ok(
-            Job {
-                display_name: job["displayName"].take_string().unwrap_or_default(),
-                result: result_from_job(job["result"].take_string()),
-            }
-        )
+    fn new(payload: String) -> Result<Job, Box<dyn Error>> {
+        Ok(Job::from_json(json::parse(payload.as_ref())?))
     }
-}
 
-pub fn find_and_track_build_and_update_status(
-    commit_ref: CommitRef,
-    jenkins_url: String,
-    jenkins_user_id: &String,
-    jenkins_token: &String,
-    github_token: String,
-) -> Result<(), Box<Error>> {
-    let jenkins_client = jenkins_request_client(
-        &jenkins_user_id,
-        &jenkins_token
-    )?;
+    fn from_json(mut job: json::JsonValue) -> Job {
+        let revisions = revisions(&job["actions"]);
 
-    let jobs = get_jobs(
-        &jenkins_url,
-        &jenkins_client,
-        commit_ref.repo.as_ref()
-    )?;
-    let t20_minutes = 60 * 20;
+        let interruption = job["actions"].members()
+            .filter(|action| action["_class"] == "jenkins.model.InterruptedBuildAction")
+            .flat_map(|action| action["causes"].members())
+            .filter_map(|cause| cause["shortDescription"].as_str())
+            .next()
+            .map(str::to_owned);
 
-    for job_url in jobs {
-        debug!("Looking for job: {}", job_url);
+        // Pipelines can set a result before they've finished running.
+        let building = job["building"].as_bool().unwrap_or(false);
+        let result = if building {
+            JobStatus::Pending
+        } else {
+            result_from_job(job["result"].take_string())
+        };
 
-        let mut job = request_job(
-            &jenkins_url,
-            &jenkins_client,
-            job_url.as_ref()
-        )?;
-
-        // Does `displayName` match
-        if job_for_commit(&job, &commit_ref) {
-            debug!("Job found: {}", job_url);
-
-            // Start timer
-            let now = Instant::now();
-
-            let commit_status = job.result.commit_status();
-
-            let job_console_url = jenkins_console_url_path(&job_url);
-
-            github::update_commit_status(
-                &github_token,
-                &commit_ref,
-                &commit_status,
-                job_console_url.clone(),
-                None,
-                "continuous-integration/jenkins".to_owned()
-            ).expect(
-                format!(
-                    "GitHub pending status update failed for {}/{} {}.",
-                    commit_ref.owner,
-                    commit_ref.repo,
-                    commit_ref.sha
-                ).as_ref()
-            );
-
-            while job.result == JobStatus::Pending {
-                // loop
-                // if timer > 20 minutes
-                //   call github::update_commit_status with timeout error
-                //   return
-                // wait 30 seconds
-                // call request_job again
-                // if the status is different
-                //   call github::update_commit_status
-                //   stop
-
-                debug!("Waiting for job to finish");
-
-                if now.elapsed().as_secs() == t20_minutes {
-                    github::update_commit_status(
-                        &github_token,
-                        &commit_ref,
-                        &github::CommitStatus::Error,
-                        job_console_url.clone(),
-                        Some("The status checker timed out.".to_owned()),
-                        "continuous-integration/jenkins".to_owned()
-                    ).expect(
-                        format!(
-                            "GitHub timeout error status update failed for {}/{} {}.",
-                            commit_ref.owner,
-                            commit_ref.repo,
-                            commit_ref.sha
-                        ).as_ref()
-                    );
-
-                    return Ok(())
-                }
+        Job {
+            number: job["number"].as_u64().unwrap_or(0),
+            url: job["url"].take_string().unwrap_or_default(),
+            display_name: job["displayName"].take_string().unwrap_or_default(),
+            result,
+            building,
+            timestamp: job["timestamp"].as_u64().unwrap_or(0),
+            revisions,
+            interruption,
+            duration: job["duration"].as_u64().unwrap_or(0),
+            estimated_duration: job["estimatedDuration"].as_u64().unwrap_or(0),
+        }
+    }
 
-                sleep(Duration::from_secs(30));
-
-                let updated_job = request_job(
-                    &jenkins_url,
-                    &jenkins_client,
-                    job_url.as_ref()
-                ).expect(
-                    format!("Failed to request job '{}'.", job_url).as_ref()
-                );
-
-                if job.result != updated_job.result {
-                    github::update_commit_status(
-                        &github_token,
-                        &commit_ref,
-                        &updated_job.result.commit_status(),
-                        job_console_url.clone(),
-                        None,
-                        "continuous-integration/jenkins".to_owned()
-                    ).expect(
-                        format!(
-                            "GitHub status update failed for {}/{} {}.",
-                            commit_ref.owner,
-                            commit_ref.repo,
-                            commit_ref.sha
-                        ).as_ref()
-                    );
-
-                    return Ok(())
-                }
+    // A sentence about the build's result for its commit status, e.g.
+    // "Build aborted by jdoe".
+    pub fn description(&self) -> String {
+        if let (&JobStatus::Aborted, Some(cause)) =
+            (&self.result, self.interruption.as_ref()) {
+            let mut chars = cause.chars();
 
-                job = updated_job;
+            if let Some(first) = chars.next() {
+                return format!("Build {}{}", first.to_lowercase(), chars.as_str())
             }
+        }
+
+        self.result.description().to_owned()
+    }
+}
+
+// The commits recorded in the actions of a build or queue item.
+fn revisions(actions: &json::JsonValue) -> Vec<String> {
+    let mut revisions = Vec::new();
+
+    for action in actions.members() {
+        if let Some(sha) = action["lastBuiltRevision"]["SHA1"].as_str() {
+            revisions.push(sha.to_owned());
+        }
+
+        for parameter in action["parameters"].members() {
+            let is_revision = parameter["name"].as_str()
+                .is_some_and(|name| REVISION_PARAMETERS.contains(&name));
 
-            return Ok(())
+            if let (true, Some(sha)) = (is_revision, parameter["value"].as_str()) {
+                revisions.push(sha.to_owned());
+            }
         }
     }
 
-    Ok(())
+    revisions
+}
+
+// A build event sent by the Jenkins Notification Plugin:
+//
+//   {
+//     "name": "kipper-branches",
+//     "build": {
+//       "full_url": "http://jenkins.example.com/job/kipper-branches/12/",
+//       "phase": "COMPLETED",
+//       "status": "SUCCESS",
+//       "scm": { "commit": "6dcb09b5b57875f334f61aebed695e2e4193db5e" }
+//     }
+//   }
+pub struct Notification {
+    pub phase: String,
+    pub build_url: String,
+    pub sha: String,
+    pub result: JobStatus,
+}
+
+impl Notification {
+    pub fn new(payload: &str) -> Result<Notification, Box<dyn Error>> {
+        let mut notification = json::parse(payload)?;
+        let build = &mut notification["build"];
+
+        let phase = build["phase"].take_string()
+            .ok_or("notification has no `build.phase`")?;
+        let build_url = build["full_url"].take_string()
+            .ok_or("notification has no `build.full_url`")?;
+        let sha = build["scm"]["commit"].take_string()
+            .ok_or("notification has no `build.scm.commit`")?;
+
+        // The status is only meaningful once the build has completed. It's
+        // also sent with STARTED, but then it's the status of the previous
+        // build.
+        let result = match phase.as_ref() {
+            "COMPLETED" | "FINALIZED" => result_from_job(build["status"].take_string()),
+            _ => JobStatus::Pending,
+        };
+
+        Ok(
+            Notification {
+                phase,
+                build_url,
+                sha,
+                result,
+            }
+        )
+    }
 }
 
 pub fn auth_credentials(user_id: String, token: String) -> header::Basic {
@@ -225,14 +295,25 @@ pub fn auth_credentials(user_id: String, token: String) -> header::Basic {
     }
 }
 
+// The latest `limit` builds of a job, newest first, with just enough of each
+// to match it to a commit. They're fetched in a single request.
 pub fn get_jobs(
     jenkins_url: &String,
-    client: &reqwest::Client,
-    repo_name: &str
-) -> Result<Vec<String>, Box<Error>> {
-    let mut response = client.get(
-        &format!("{}/job/{}-branches/api/json", jenkins_url, repo_name)
-    ).send()?;
+    client: &http::Client,
+    job_path: &str,
+    limit: usize,
+) -> Result<Vec<Job>, Box<dyn Error>> {
+    let url = format!(
+        "{}{}/api/json?tree=builds[{}]{{0,{}}}",
+        jenkins_url,
+        job_path,
+        BUILD_TREE,
+        limit
+    );
+
+    let mut response = http::send(client, &RetryPolicy::default(), || {
+        client.get(&url).build()
+    })?;
 
     let body = response.text()?;
 
@@ -240,23 +321,27 @@ pub fn get_jobs(
 
     Ok(
         jobs["builds"].members()
-            .map(|job| {
-                job["url"].clone().take_string().unwrap_or_default()
-            })
-            .collect::<Vec<String>>()
+            .map(|job| Job::from_json(job.clone()))
+            .collect()
     )
 }
 
 pub fn request_job(
     jenkins_url: &String,
-    client: &reqwest::Client,
+    client: &http::Client,
     url: &str
-) -> Result<Job, Box<Error>> {
-    let url = Url::parse(url.as_ref())?;
+) -> Result<Job, Box<dyn Error>> {
+    let url = Url::parse(url)?;
+
+    let url = format!(
+        "{}{}/api/json",
+        jenkins_url,
+        url.path().trim_end_matches('/')
+    );
 
-    let mut response = client.get(
-        &format!("{}{}/api/json", jenkins_url, url.path())
-    ).send()?;
+    let mut response = http::send(client, &RetryPolicy::default(), || {
+        client.get(&url).build()
+    })?;
 
     let body = response.text()?;
 
@@ -265,21 +350,251 @@ pub fn request_job(
     Ok(job)
 }
 
+// Start a build of the job at `job_path` for the commit, returning the URL of
+// its queue item. The build is given the parameters `branch`, `sha1`,
+// `number` and `base_branch`, the last two only for pull requests.
+pub fn trigger_build(
+    jenkins_url: &String,
+    client: &http::Client,
+    job_path: &str,
+    commit_ref: &CommitRef,
+) -> Result<String, Box<dyn Error>> {
+    let mut params = vec![
+        ("branch", commit_ref.branch.clone()),
+        ("sha1", commit_ref.sha.clone()),
+    ];
+
+    if let Some(number) = commit_ref.number {
+        params.push(("number", number.to_string()));
+    }
+
+    if let Some(ref base_branch) = commit_ref.base_branch {
+        params.push(("base_branch", base_branch.clone()));
+    }
+
+    let url = Url::parse_with_params(
+        &format!("{}{}/buildWithParameters", jenkins_url, job_path),
+        &params
+    )?;
+
+    let mut headers = header::Headers::new();
+
+    if let Some((field, crumb)) = get_crumb(jenkins_url, client)? {
+        headers.set_raw(field, crumb);
+    }
+
+    // Jenkins may have queued the build even if the request failed, so it
+    // isn't retried, which would queue another.
+    let response = http::send(client, &RetryPolicy::default(), || {
+        client.post(url.as_str())
+            .headers(headers.clone())
+            .build()
+    })?;
+
+    match response.headers().get::<header::Location>() {
+        Some(location) => Ok(location.to_string()),
+        None => Err(
+            format!("Jenkins didn't say where {} was queued", url.path()).into()
+        ),
+    }
+}
+
+// The header and value Jenkins expects on POST requests to protect against
+// CSRF, or `None` if protection is disabled.
+fn get_crumb(
+    jenkins_url: &String,
+    client: &http::Client,
+) -> Result<Option<(String, String)>, Box<dyn Error>> {
+    let url = format!("{}/crumbIssuer/api/json", jenkins_url);
+
+    let result = http::send(client, &RetryPolicy::default(), || {
+        client.get(&url).build()
+    });
+
+    let mut response = match result {
+        Ok(response) => response,
+        Err(ref e) if is_not_found(e.as_ref()) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let body = response.text()?;
+
+    let mut crumb = json::parse(body.as_ref())?;
+
+    match (
+        crumb["crumbRequestField"].take_string(),
+        crumb["crumb"].take_string(),
+    ) {
+        (Some(field), Some(value)) => Ok(Some((field, value))),
+        _ => Err("invalid crumb from Jenkins".into()),
+    }
+}
+
+// A build waiting to be run. Once it starts, Jenkins fills in the URL of the
+// build.
+#[derive(Debug, Default, PartialEq)]
+pub struct QueueItem {
+    pub build_url: Option<String>,
+    pub cancelled: bool,
+
+    // Why the build hasn't started yet, e.g. "Waiting for next available
+    // executor".
+    pub why: Option<String>,
+}
+
+// The URL of the queue item for a build of the job at `job_path` for the
+// commit, if one is waiting. Items can only be told apart by a `GIT_COMMIT`
+// or `sha1` parameter.
+pub fn find_queue_item(
+    jenkins_url: &String,
+    client: &http::Client,
+    job_path: &str,
+    commit_ref: &CommitRef,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let url = format!("{}/queue/api/json?tree={}", jenkins_url, QUEUE_TREE);
+
+    let mut response = http::send(client, &RetryPolicy::default(), || {
+        client.get(&url).build()
+    })?;
+
+    let body = response.text()?;
+
+    let queue = json::parse(body.as_ref())?;
+
+    let item = queue["items"].members()
+        .filter(|item| {
+            item["task"]["url"].as_str()
+                .and_then(|url| Url::parse(url).ok())
+                .is_some_and(|url| {
+                    url.path().trim_end_matches('/').ends_with(job_path)
+                })
+        })
+        .find(|item| {
+            revisions(&item["actions"]).iter()
+                .any(|sha| sha.eq_ignore_ascii_case(&commit_ref.sha))
+        });
+
+    Ok(
+        item.and_then(|item| item["url"].as_str())
+            .map(|path| {
+                format!("{}/{}", jenkins_url.trim_end_matches('/'), path)
+            })
+    )
+}
+
+pub fn get_queue_item(
+    jenkins_url: &String,
+    client: &http::Client,
+    queue_url: &str,
+) -> Result<QueueItem, Box<dyn Error>> {
+    let url = Url::parse(queue_url)?;
+
+    let url = format!(
+        "{}{}/api/json",
+        jenkins_url,
+        url.path().trim_end_matches('/')
+    );
+
+    let mut response = http::send(client, &RetryPolicy::default(), || {
+        client.get(&url).build()
+    })?;
+
+    let body = response.text()?;
+
+    let mut item = json::parse(body.as_ref())?;
+
+    Ok(
+        QueueItem {
+            build_url: item["executable"]["url"].take_string(),
+            cancelled: item["cancelled"].as_bool().unwrap_or(false),
+            why: item["why"].take_string(),
+        }
+    )
+}
+
+// A failed test case from a build's test report.
+#[derive(Debug, PartialEq)]
+pub struct TestFailure {
+    pub name: String,
+    pub details: String,
+    pub stack_trace: String,
+}
+
+// The failed tests of a build, from the JUnit plugin's test report. Builds
+// without a test report have no failures.
+pub fn get_test_failures(
+    jenkins_url: &String,
+    client: &http::Client,
+    build_url: &str
+) -> Result<Vec<TestFailure>, Box<dyn Error>> {
+    let url = Url::parse(build_url)?;
+
+    let url = format!(
+        "{}{}/testReport/api/json",
+        jenkins_url,
+        url.path().trim_end_matches('/')
+    );
+
+    let result = http::send(client, &RetryPolicy::default(), || {
+        client.get(&url).build()
+    });
+
+    let mut response = match result {
+        Ok(response) => response,
+        Err(ref e) if is_not_found(e.as_ref()) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let body = response.text()?;
+
+    let report = json::parse(body.as_ref())?;
+
+    let failures = report["suites"].members()
+        .flat_map(|suite| suite["cases"].members())
+        .filter(|case| {
+            matches!(case["status"].as_str(), Some("FAILED") | Some("REGRESSION"))
+        })
+        .map(|case| {
+            let class_name = case["className"].as_str().unwrap_or_default();
+            let name = case["name"].as_str().unwrap_or_default();
+
+            TestFailure {
+                name: if class_name.is_empty() {
+                    name.to_owned()
+                } else {
+                    format!("{}.{}", class_name, name)
+                },
+                details: case["errorDetails"].as_str()
+                    .unwrap_or_default()
+                    .to_owned(),
+                stack_trace: case["errorStackTrace"].as_str()
+                    .unwrap_or_default()
+                    .to_owned(),
+            }
+        })
+        .collect();
+
+    Ok(failures)
+}
+
+fn is_not_found(error: &(dyn Error + 'static)) -> bool {
+    error.downcast_ref::<HttpError>()
+        .is_some_and(|e| e.status == reqwest::StatusCode::NotFound)
+}
+
 // Does the `commit_ref` correspond to the job?
-pub fn job_for_commit(job: &Job, commit_ref: &CommitRef) -> bool {
-    job.display_name == af83::job_name(&commit_ref)
+pub fn job_for_commit(
+    job: &Job,
+    commit_ref: &CommitRef,
+    matcher: &dyn JobMatcher,
+) -> bool {
+    matcher.matches(job, commit_ref)
 }
 
 pub fn result_from_job(status: Option<String>) -> JobStatus {
     match status {
         None => JobStatus::Pending,
-        Some(s) => {
-            match s.as_ref() {
-                "SUCCESS" => JobStatus::Success,
-                "FAILURE" => JobStatus::Failure,
-                _ => JobStatus::Unknown,
-            }
-        }
+        Some(s) => s.parse().unwrap_or(JobStatus::Unknown),
     }
 }
 
@@ -288,7 +603,7 @@ pub fn jenkins_console_url_path(job_url: &String) -> String {
 }
 
 
-fn jenkins_request_client(user_id: &String, token: &String) -> Result<reqwest::Client, Box<Error>> {
+pub fn jenkins_request_client(user_id: &String, token: &String) -> Result<http::Client<'static>, Box<dyn Error>> {
     let credentials = auth_credentials(user_id.to_owned(), token.to_owned());
 
     let mut headers = header::Headers::new();
@@ -298,17 +613,19 @@ fn jenkins_request_client(user_id: &String, token: &String) -> Result<reqwest::C
         .default_headers(headers)
         .build()?;
 
-    Ok(client)
+    Ok(http::Client::new(client))
 }
 
 
 #[cfg(test)]
 mod tests {
-    use self::mockito::mock;
+    use self::mockito::{mock, Matcher};
+
+    use job_matcher::Af83;
 
     use super::*;
 
-    fn test_request_client() -> reqwest::Client {
+    fn test_request_client() -> http::Client<'static> {
         jenkins_request_client(
             &"username".to_owned(),
             &"token".to_owned()
@@ -328,23 +645,243 @@ mod tests {
         assert_eq!(job.result, JobStatus::Success);
     }
 
+    #[test]
+    fn job_new_is_pending_while_building() {
+        let payload = r#"{
+            "displayName": "3296-fix-typo-700d0",
+            "building": true,
+            "result": "FAILURE"
+        }"#.to_owned();
+
+        let job = Job::new(payload).expect("Failed to create job from payload");
+
+        assert!(job.building);
+        assert_eq!(job.result, JobStatus::Pending);
+        assert_eq!(job.description(), "Build in progress");
+    }
+
+    #[test]
+    fn job_description_says_who_aborted_the_build() {
+        let payload = r#"{
+            "_class": "hudson.model.FreeStyleBuild",
+            "actions": [
+                {
+                    "_class": "hudson.model.CauseAction",
+                    "causes": [
+                        { "shortDescription": "Started by GitHub push by octocat" }
+                    ]
+                },
+                {
+                    "_class": "jenkins.model.InterruptedBuildAction",
+                    "causes": [
+                        {
+                            "_class": "jenkins.model.CauseOfInterruption$UserInterruption",
+                            "shortDescription": "Aborted by jdoe"
+                        }
+                    ]
+                }
+            ],
+            "building": false,
+            "displayName": "3296-fix-typo-700d0",
+            "result": "ABORTED"
+        }"#.to_owned();
+
+        let job = Job::new(payload).expect("Failed to create job from payload");
+
+        assert_eq!(job.result, JobStatus::Aborted);
+        assert_eq!(job.description(), "Build aborted by jdoe");
+
+        let job = Job {
+            result: JobStatus::Aborted,
+            ..Job::default()
+        };
+
+        assert_eq!(job.description(), "Build aborted");
+    }
+
+    #[test]
+    fn notification_new_reads_completed_build() {
+        let payload = r#"{
+            "name": "changes-branches",
+            "url": "job/changes-branches/",
+            "build": {
+                "full_url": "http://jenkins.example.com/job/changes-branches/21/",
+                "number": 21,
+                "phase": "COMPLETED",
+                "status": "FAILURE",
+                "url": "job/changes-branches/21/",
+                "scm": {
+                    "url": "git@github.com:octocat/changes.git",
+                    "branch": "origin/2388-delete-the-codes",
+                    "commit": "391afd5bc4d8c5f4a5e1b9d5f2a1c3e7b6d8f0a2"
+                }
+            }
+        }"#;
+
+        let notification = Notification::new(payload)
+            .expect("Failed to parse notification");
+
+        assert_eq!(notification.phase, "COMPLETED");
+        assert_eq!(
+            notification.build_url,
+            "http://jenkins.example.com/job/changes-branches/21/"
+        );
+        assert_eq!(notification.sha, "391afd5bc4d8c5f4a5e1b9d5f2a1c3e7b6d8f0a2");
+        assert_eq!(notification.result, JobStatus::Failure);
+    }
+
+    #[test]
+    fn notification_new_ignores_status_of_started_build() {
+        let payload = r#"{
+            "build": {
+                "full_url": "http://jenkins.example.com/job/changes-branches/22/",
+                "phase": "STARTED",
+                "status": "FAILURE",
+                "scm": { "commit": "391afd5bc4d8c5f4a5e1b9d5f2a1c3e7b6d8f0a2" }
+            }
+        }"#;
+
+        let notification = Notification::new(payload)
+            .expect("Failed to parse notification");
+
+        assert_eq!(notification.result, JobStatus::Pending);
+    }
+
+    #[test]
+    fn notification_new_requires_commit() {
+        let payload = r#"{
+            "build": {
+                "full_url": "http://jenkins.example.com/job/changes-branches/22/",
+                "phase": "STARTED"
+            }
+        }"#;
+
+        assert!(Notification::new(payload).is_err());
+    }
+
+    #[test]
+    fn get_test_failures_lists_failed_cases() {
+        let _mock = mock("GET", "/job/changes-branches/16/testReport/api/json")
+            .with_status(200)
+            .with_header("content-type", "application/json;charset=utf-8")
+            .with_body(r#"
+                {
+                  "failCount": 2,
+                  "suites": [
+                    {
+                      "cases": [
+                        {
+                          "className": "spec.models.widget_spec",
+                          "name": "renders the logo",
+                          "status": "REGRESSION",
+                          "errorDetails": "expected 42, got 41",
+                          "errorStackTrace": "./spec/models/widget_spec.rb:12:in `block'"
+                        },
+                        {
+                          "className": "spec.models.widget_spec",
+                          "name": "saves",
+                          "status": "PASSED"
+                        }
+                      ]
+                    },
+                    {
+                      "cases": [
+                        {
+                          "className": "",
+                          "name": "lint",
+                          "status": "FAILED"
+                        }
+                      ]
+                    }
+                  ]
+                }
+            "#)
+            .create();
+
+        let failures = get_test_failures(
+            &mockito::SERVER_URL.to_owned(),
+            &test_request_client(),
+            "http://jenkins.example.com/job/changes-branches/16/"
+        ).expect("Failed to request test report");
+
+        assert_eq!(
+            failures,
+            [
+                TestFailure {
+                    name: "spec.models.widget_spec.renders the logo".to_owned(),
+                    details: "expected 42, got 41".to_owned(),
+                    stack_trace: "./spec/models/widget_spec.rb:12:in `block'".to_owned(),
+                },
+                TestFailure {
+                    name: "lint".to_owned(),
+                    details: "".to_owned(),
+                    stack_trace: "".to_owned(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn get_test_failures_is_empty_without_test_report() {
+        let _mock = mock("GET", "/job/changes-branches/17/testReport/api/json")
+            .with_status(404)
+            .create();
+
+        let failures = get_test_failures(
+            &mockito::SERVER_URL.to_owned(),
+            &test_request_client(),
+            "http://jenkins.example.com/job/changes-branches/17/"
+        ).expect("Failed to request test report");
+
+        assert!(failures.is_empty());
+    }
+
     #[test]
     fn get_jobs_queries_jobs_from_jenkins_api() {
-        let _mock = mock("GET", "/job/changes-branches/api/json")
+        let _mock = mock(
+            "GET",
+            "/job/changes-branches/api/json?tree=builds[number,url,displayName,result,building,timestamp,actions[lastBuiltRevision[SHA1],parameters[name,value]]]{0,50}"
+        )
             .with_status(200)
             .with_header("content-type", "application/json;charset=utf-8")
             .with_body(r#"
                 {
-                  "displayName": "changes-branches",
+                  "_class": "hudson.model.FreeStyleProject",
                   "builds": [
                     {
                       "_class": "hudson.model.FreeStyleBuild",
+                      "actions": [
+                        {},
+                        {
+                          "_class": "hudson.plugins.git.util.BuildData",
+                          "lastBuiltRevision": {
+                            "SHA1": "b4a286e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c"
+                          }
+                        }
+                      ],
+                      "building": true,
+                      "displayName": "1753-fix-everything-b4a28",
                       "number": 18,
+                      "result": null,
+                      "timestamp": 1511352000000,
                       "url": "http://jenkins.example.com/job/changes-branches/18/"
                     },
                     {
                       "_class": "hudson.model.FreeStyleBuild",
+                      "actions": [
+                        {
+                          "_class": "hudson.model.ParametersAction",
+                          "parameters": [
+                            { "name": "BRANCH", "value": "2388-delete-the-codes" },
+                            { "name": "sha1", "value": "391af2d5b4c8e0f1a7e9c6b3d2a1f0e9d8c7b6a5" }
+                          ]
+                        }
+                      ],
+                      "building": false,
+                      "displayName": "2388-delete-the-codes-391af",
                       "number": 17,
+                      "result": "SUCCESS",
+                      "timestamp": 1511351000000,
                       "url": "http://jenkins.example.com/job/changes-branches/17/"
                     }
                   ]
@@ -355,15 +892,32 @@ mod tests {
         let jobs = get_jobs(
             &mockito::SERVER_URL.to_owned(),
             &test_request_client(),
-            "changes"
+            "/job/changes-branches",
+            50
         ).expect("Failed to request jobs");
 
+        assert_eq!(jobs.len(), 2);
+
+        assert_eq!(jobs[0].number, 18);
         assert_eq!(
-            jobs,
-            [
-                "http://jenkins.example.com/job/changes-branches/18/",
-                "http://jenkins.example.com/job/changes-branches/17/"
-            ]
+            jobs[0].url,
+            "http://jenkins.example.com/job/changes-branches/18/"
+        );
+        assert_eq!(jobs[0].display_name, "1753-fix-everything-b4a28");
+        assert_eq!(jobs[0].result, JobStatus::Pending);
+        assert!(jobs[0].building);
+        assert_eq!(jobs[0].timestamp, 1511352000000);
+        assert_eq!(
+            jobs[0].revisions,
+            ["b4a286e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c"]
+        );
+
+        assert_eq!(jobs[1].number, 17);
+        assert_eq!(jobs[1].result, JobStatus::Success);
+        assert!(!jobs[1].building);
+        assert_eq!(
+            jobs[1].revisions,
+            ["391af2d5b4c8e0f1a7e9c6b3d2a1f0e9d8c7b6a5"]
         );
     }
 
@@ -389,6 +943,7 @@ mod tests {
         let expected = Job {
             display_name: "2388-delete-the-codes-391af".to_owned(),
             result: JobStatus::Success,
+            ..Job::default()
         };
 
         assert_eq!(job.display_name, expected.display_name);
@@ -400,6 +955,7 @@ mod tests {
         let job = Job {
             display_name: "1753-fix-everything-b4a28".to_owned(),
             result: JobStatus::Pending,
+            ..Job::default()
         };
 
         let commit_ref = CommitRef {
@@ -407,9 +963,10 @@ mod tests {
             repo: "vivid-system".to_owned(),
             sha: "b4a286e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c".to_owned(),
             branch: "1753-fix-everything".to_owned(),
+            ..CommitRef::test_default()
         };
 
-        assert_eq!(job_for_commit(&job, &commit_ref), true);
+        assert!(job_for_commit(&job, &commit_ref, &Af83));
     }
 
     #[test]
@@ -417,6 +974,7 @@ mod tests {
         let job = Job {
             display_name: "5234-eliminate-widgetmacallit-5a28c".to_owned(),
             result: JobStatus::Success,
+            ..Job::default()
         };
 
         let commit_ref = CommitRef {
@@ -424,9 +982,255 @@ mod tests {
             repo: "vivid-system".to_owned(),
             sha: "b4a286e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c".to_owned(),
             branch: "1753-fix-everything".to_owned(),
+            ..CommitRef::test_default()
         };
 
-        assert_eq!(job_for_commit(&job, &commit_ref), false);
+        assert!(!job_for_commit(&job, &commit_ref, &Af83));
+    }
+
+    fn trigger_commit_ref() -> CommitRef {
+        CommitRef {
+            owner: "uso".to_owned(),
+            repo: "trigger-system".to_owned(),
+            sha: "b4a286e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c".to_owned(),
+            branch: "1753-fix-everything".to_owned(),
+            base_branch: Some("master".to_owned()),
+            number: Some(12),
+            ..CommitRef::test_default()
+        }
+    }
+
+    #[test]
+    fn trigger_build_sends_crumb_and_returns_queue_item() {
+        let _crumb = mock("GET", "/crumbIssuer/api/json")
+            .with_status(200)
+            .with_header("content-type", "application/json;charset=utf-8")
+            .with_body(r#"
+                {
+                  "_class": "hudson.security.csrf.DefaultCrumbIssuer",
+                  "crumb": "4f1e6a0c9b2d",
+                  "crumbRequestField": "Jenkins-Crumb"
+                }
+            "#)
+            .create();
+
+        let build = mock(
+                "POST",
+                "/job/trigger-system-branches/buildWithParameters?branch=1753-fix-everything&sha1=b4a286e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c&number=12&base_branch=master"
+            )
+            .match_header("Jenkins-Crumb", "4f1e6a0c9b2d")
+            .with_status(201)
+            .with_header("location", "http://jenkins.example.com/queue/item/41/")
+            .create();
+
+        let queue_url = trigger_build(
+            &mockito::SERVER_URL.to_owned(),
+            &test_request_client(),
+            "/job/trigger-system-branches",
+            &trigger_commit_ref()
+        ).expect("Failed to trigger build");
+
+        build.assert();
+        assert_eq!(queue_url, "http://jenkins.example.com/queue/item/41/");
+    }
+
+    #[test]
+    fn trigger_build_without_csrf_protection() {
+        // Served under a prefix so as not to share the crumb issuer with
+        // other tests.
+        let jenkins_url = format!("{}/jenkins", mockito::SERVER_URL);
+
+        let _crumb = mock("GET", "/jenkins/crumbIssuer/api/json")
+            .with_status(404)
+            .create();
+
+        let mut commit_ref = trigger_commit_ref();
+        commit_ref.repo = "no-crumb-system".to_owned();
+        commit_ref.branch = "feature/login".to_owned();
+        commit_ref.base_branch = None;
+        commit_ref.number = None;
+
+        let build = mock(
+                "POST",
+                "/jenkins/job/no-crumb-system-branches/buildWithParameters?branch=feature%2Flogin&sha1=b4a286e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c"
+            )
+            .with_status(201)
+            .with_header("location", "http://jenkins.example.com/queue/item/42/")
+            .create();
+
+        let queue_url = trigger_build(
+            &jenkins_url,
+            &test_request_client(),
+            "/job/no-crumb-system-branches",
+            &commit_ref
+        ).expect("Failed to trigger build");
+
+        build.assert();
+        assert_eq!(queue_url, "http://jenkins.example.com/queue/item/42/");
+    }
+
+    #[test]
+    fn trigger_build_is_not_retried() {
+        let jenkins_url = format!("{}/unretried", mockito::SERVER_URL);
+
+        let _crumb = mock("GET", "/unretried/crumbIssuer/api/json")
+            .with_status(404)
+            .create();
+
+        let build = mock(
+                "POST",
+                Matcher::Regex(
+                    r"^/unretried/job/trigger-system-branches/buildWithParameters\?".to_owned()
+                )
+            )
+            .with_status(502)
+            .with_header("connection", "close")
+            .expect(1)
+            .create();
+
+        let error = trigger_build(
+            &jenkins_url,
+            &test_request_client(),
+            "/job/trigger-system-branches",
+            &trigger_commit_ref()
+        ).expect_err("Build should fail to trigger");
+
+        build.assert();
+        assert_eq!(
+            error.downcast_ref::<HttpError>().map(|e| e.status),
+            Some(reqwest::StatusCode::BadGateway)
+        );
+    }
+
+    #[test]
+    fn find_queue_item_matches_job_and_commit() {
+        let jenkins_url = format!("{}/queued", mockito::SERVER_URL);
+
+        let _queue = mock(
+                "GET",
+                "/queued/queue/api/json?tree=items[url,why,task[url],actions[parameters[name,value]]]"
+            )
+            .with_status(200)
+            .with_body(r#"
+                {
+                  "_class": "hudson.model.Queue",
+                  "items": [
+                    {
+                      "actions": [
+                        {
+                          "parameters": [
+                            { "name": "sha1", "value": "b4a286e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c" }
+                          ]
+                        }
+                      ],
+                      "task": { "url": "http://jenkins.example.com/job/other-system-branches/" },
+                      "url": "queue/item/61/",
+                      "why": "Waiting for next available executor"
+                    },
+                    {
+                      "actions": [
+                        {
+                          "parameters": [
+                            { "name": "sha1", "value": "5a28c0e9d8c7b6a5f4e3d2c1b0a9f8e7d6c5b4a3" }
+                          ]
+                        }
+                      ],
+                      "task": { "url": "http://jenkins.example.com/job/trigger-system-branches/" },
+                      "url": "queue/item/62/",
+                      "why": "Waiting for next available executor"
+                    },
+                    {
+                      "actions": [
+                        { "causes": [] },
+                        {
+                          "parameters": [
+                            { "name": "GIT_COMMIT", "value": "b4a286e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c" }
+                          ]
+                        }
+                      ],
+                      "task": { "url": "http://jenkins.example.com/job/trigger-system-branches/" },
+                      "url": "queue/item/63/",
+                      "why": "Waiting for next available executor"
+                    }
+                  ]
+                }
+            "#)
+            .create();
+
+        let queue_url = find_queue_item(
+            &jenkins_url,
+            &test_request_client(),
+            "/job/trigger-system-branches",
+            &trigger_commit_ref()
+        ).expect("Failed to request queue");
+
+        assert_eq!(queue_url, Some(format!("{}/queue/item/63/", jenkins_url)));
+
+        let mut commit_ref = trigger_commit_ref();
+        commit_ref.sha = "0d1a26e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c".to_owned();
+
+        let queue_url = find_queue_item(
+            &jenkins_url,
+            &test_request_client(),
+            "/job/trigger-system-branches",
+            &commit_ref
+        ).expect("Failed to request queue");
+
+        assert_eq!(queue_url, None);
+    }
+
+    #[test]
+    fn get_queue_item_follows_item_to_its_build() {
+        let _waiting = mock("GET", "/queue/item/43/api/json")
+            .with_status(200)
+            .with_body(r#"
+                {
+                  "_class": "hudson.model.Queue$WaitingItem",
+                  "cancelled": false,
+                  "executable": null,
+                  "why": "In the quiet period."
+                }
+            "#)
+            .create();
+
+        let item = get_queue_item(
+            &mockito::SERVER_URL.to_owned(),
+            &test_request_client(),
+            "http://jenkins.example.com/queue/item/43/"
+        ).expect("Failed to request queue item");
+
+        assert_eq!(
+            item,
+            QueueItem {
+                why: Some("In the quiet period.".to_owned()),
+                ..QueueItem::default()
+            }
+        );
+
+        let _started = mock("GET", "/queue/item/43/api/json")
+            .with_status(200)
+            .with_body(r#"
+                {
+                  "_class": "hudson.model.Queue$LeftItem",
+                  "cancelled": false,
+                  "executable": {
+                    "number": 19,
+                    "url": "http://jenkins.example.com/job/trigger-system-branches/19/"
+                  }
+                }
+            "#)
+            .create();
+
+        let item = get_queue_item(
+            &mockito::SERVER_URL.to_owned(),
+            &test_request_client(),
+            "http://jenkins.example.com/queue/item/43/"
+        ).expect("Failed to request queue item");
+
+        assert_eq!(
+            item.build_url,
+            Some("http://jenkins.example.com/job/trigger-system-branches/19/".to_owned())
+        );
     }
 
     #[test]
@@ -453,6 +1257,26 @@ mod tests {
         );
     }
 
+    #[test]
+    fn result_from_job_reads_other_results() {
+        assert_eq!(
+            result_from_job(Some("UNSTABLE".to_owned())),
+            JobStatus::Unstable
+        );
+        assert_eq!(
+            result_from_job(Some("ABORTED".to_owned())),
+            JobStatus::Aborted
+        );
+        assert_eq!(
+            result_from_job(Some("NOT_BUILT".to_owned())),
+            JobStatus::NotBuilt
+        );
+        assert_eq!(
+            result_from_job(Some("EXPLODED".to_owned())),
+            JobStatus::Unknown
+        );
+    }
+
     #[test]
     fn jenkins_console_url_path_returns_url_to_console_page() {
         assert_eq!(
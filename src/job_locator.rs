@@ -0,0 +1,228 @@
+// Copyright © 2017 Teddy Wing
+//
+// This file is part of Kipper.
+//
+// Kipper is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Kipper is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kipper. If not, see <http://www.gnu.org/licenses/>.
+
+extern crate url;
+
+use std::error::Error;
+
+use self::url::percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+
+use job_matcher::Template;
+use pull_request::CommitRef;
+
+// Finds the Jenkins job whose builds may belong to a commit. Builds are then
+// picked out of that job by a `JobMatcher`.
+pub trait JobLocator {
+    // The path of the job relative to the Jenkins URL, e.g.
+    // `/job/kipper-branches`. Returns `None` when the commit has no job in
+    // this layout, like a push outside of a pull request for `PR-{number}`
+    // jobs.
+    fn job_path(&self, commit_ref: &CommitRef) -> Option<String>;
+}
+
+// A freestyle "branches" project named after the repository, building every
+// branch: `/job/{repo}-branches`.
+pub struct BranchesProject;
+
+impl JobLocator for BranchesProject {
+    fn job_path(&self, commit_ref: &CommitRef) -> Option<String> {
+        Some(
+            format!(
+                "/job/{}",
+                utf8_percent_encode(
+                    &format!("{}-branches", commit_ref.repo),
+                    PATH_SEGMENT_ENCODE_SET
+                )
+            )
+        )
+    }
+}
+
+// A job nested in folders, as created by Multibranch Pipelines and
+// organization folders. Each `/`-separated segment of the template is a level
+// of nesting, so `{owner}/{repo}/PR-{number}` locates
+// `/job/octocat/job/Hello-World/job/PR-1347`.
+pub struct FolderPath {
+    segments: Vec<Template>,
+}
+
+impl FolderPath {
+    pub fn new(path: &str) -> Result<FolderPath, Box<dyn Error>> {
+        let segments = path.trim_matches('/')
+            .split('/')
+            .map(Template::parse)
+            .collect::<Result<Vec<Template>, Box<dyn Error>>>()?;
+
+        if path.trim_matches('/').is_empty() || path.contains("//") {
+            return Err(format!("invalid job path `{}`", path).into())
+        }
+
+        Ok(FolderPath { segments })
+    }
+}
+
+impl JobLocator for FolderPath {
+    fn job_path(&self, commit_ref: &CommitRef) -> Option<String> {
+        let mut path = String::new();
+
+        for segment in &self.segments {
+            let name = segment.expand_with(commit_ref, item_name)?;
+
+            path.push_str("/job/");
+            path.extend(utf8_percent_encode(&name, PATH_SEGMENT_ENCODE_SET));
+        }
+
+        Some(path)
+    }
+}
+
+// Multibranch projects name branch jobs after the branch with `%` and `/`
+// escaped, so `feature/login` becomes the job `feature%2Flogin`, which is
+// then escaped again in URLs.
+fn item_name(value: &str) -> String {
+    value.replace('%', "%25")
+        .replace('/', "%2F")
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate mockito;
+
+    use self::mockito::{mock, Matcher};
+
+    use jenkins;
+
+    use super::*;
+
+    fn commit_ref() -> CommitRef {
+        CommitRef {
+            branch: "feature/new-topic".to_owned(),
+            number: Some(1347),
+            ..CommitRef::test_default()
+        }
+    }
+
+    fn builds_of(job_path: &str) -> Matcher {
+        Matcher::Regex(format!(r"^{}/api/json\?tree=builds\[", job_path))
+    }
+
+    fn builds_in(job_path: &str) -> Vec<String> {
+        let client = jenkins::jenkins_request_client(
+            &"username".to_owned(),
+            &"token".to_owned()
+        ).expect("Failed to build Jenkins request client");
+
+        jenkins::get_jobs(&mockito::SERVER_URL.to_owned(), &client, job_path, 10)
+            .expect("Failed to request jobs")
+            .into_iter()
+            .map(|job| job.url)
+            .collect()
+    }
+
+    #[test]
+    fn branches_project_is_named_after_the_repository() {
+        let _mock = mock("GET", builds_of("/job/Hello-World-branches"))
+            .with_status(200)
+            .with_body(r#"{"builds": [{"url": "http://jenkins.example.com/job/Hello-World-branches/4/"}]}"#)
+            .create();
+
+        let path = BranchesProject.job_path(&commit_ref())
+            .expect("No job path");
+
+        assert_eq!(path, "/job/Hello-World-branches");
+        assert_eq!(
+            builds_in(&path),
+            ["http://jenkins.example.com/job/Hello-World-branches/4/"]
+        );
+    }
+
+    #[test]
+    fn folder_path_locates_multibranch_pull_request_jobs() {
+        let _mock = mock("GET", builds_of("/job/octocat/job/Hello-World/job/PR-1347"))
+            .with_status(200)
+            .with_body(r#"{"builds": [{"url": "http://jenkins.example.com/job/octocat/job/Hello-World/job/PR-1347/2/"}]}"#)
+            .create();
+
+        let locator = FolderPath::new("{owner}/{repo}/PR-{number}")
+            .expect("Failed to parse job path");
+        let path = locator.job_path(&commit_ref())
+            .expect("No job path");
+
+        assert_eq!(path, "/job/octocat/job/Hello-World/job/PR-1347");
+        assert_eq!(
+            builds_in(&path),
+            ["http://jenkins.example.com/job/octocat/job/Hello-World/job/PR-1347/2/"]
+        );
+    }
+
+    #[test]
+    fn folder_path_encodes_branch_names_with_slashes() {
+        let _mock = mock("GET", builds_of("/job/Hello-World/job/feature%252Fnew-topic"))
+            .with_status(200)
+            .with_body(r#"{"builds": [{"url": "http://jenkins.example.com/job/Hello-World/job/feature%252Fnew-topic/9/"}]}"#)
+            .create();
+
+        let locator = FolderPath::new("{repo}/{branch}")
+            .expect("Failed to parse job path");
+        let path = locator.job_path(&commit_ref())
+            .expect("No job path");
+
+        assert_eq!(path, "/job/Hello-World/job/feature%252Fnew-topic");
+        assert_eq!(
+            builds_in(&path),
+            ["http://jenkins.example.com/job/Hello-World/job/feature%252Fnew-topic/9/"]
+        );
+    }
+
+    #[test]
+    fn folder_path_supports_nested_folders() {
+        let _mock = mock("GET", builds_of("/job/Mobile%20Team/job/apps/job/Hello-World/job/PR-1347"))
+            .with_status(200)
+            .with_body(r#"{"builds": []}"#)
+            .create();
+
+        let locator = FolderPath::new("Mobile Team/apps/{repo}/PR-{number}")
+            .expect("Failed to parse job path");
+        let path = locator.job_path(&commit_ref())
+            .expect("No job path");
+
+        assert_eq!(
+            path,
+            "/job/Mobile%20Team/job/apps/job/Hello-World/job/PR-1347"
+        );
+        assert!(builds_in(&path).is_empty());
+    }
+
+    #[test]
+    fn folder_path_needs_pull_request_number() {
+        let locator = FolderPath::new("{repo}/PR-{number}")
+            .expect("Failed to parse job path");
+
+        let mut push = commit_ref();
+        push.number = None;
+
+        assert_eq!(locator.job_path(&push), None);
+    }
+
+    #[test]
+    fn folder_path_rejects_empty_segments() {
+        assert!(FolderPath::new("").is_err());
+        assert!(FolderPath::new("{owner}//{repo}").is_err());
+        assert!(FolderPath::new("{owner}/{project}").is_err());
+    }
+}
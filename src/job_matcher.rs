@@ -0,0 +1,404 @@
+// Copyright © 2017 Teddy Wing
+//
+// This file is part of Kipper.
+//
+// Kipper is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Kipper is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kipper. If not, see <http://www.gnu.org/licenses/>.
+
+extern crate regex;
+
+use std::error::Error;
+
+use self::regex::Regex;
+
+use af83;
+use jenkins::Job;
+use pull_request::CommitRef;
+
+// Decides whether a Jenkins build belongs to a commit. Repositories name their
+// builds differently, so the strategy is chosen per repository in the
+// configuration.
+pub trait JobMatcher {
+    fn matches(&self, job: &Job, commit_ref: &CommitRef) -> bool;
+
+    // The builds that would match, for when none is found, e.g. "named
+    // `new-topic-6dcb0`".
+    fn describe(&self, commit_ref: &CommitRef) -> String;
+}
+
+// The af83 convention: the branch name followed by the first five characters
+// of the commit SHA.
+pub struct Af83;
+
+impl JobMatcher for Af83 {
+    fn matches(&self, job: &Job, commit_ref: &CommitRef) -> bool {
+        job.display_name == af83::job_name(commit_ref)
+    }
+
+    fn describe(&self, commit_ref: &CommitRef) -> String {
+        format!("named `{}`", af83::job_name(commit_ref))
+    }
+}
+
+// Matches builds by the commit they were run for, when Jenkins recorded it,
+// and otherwise by their name.
+pub struct RevisionMatcher<M> {
+    fallback: M,
+}
+
+impl<M: JobMatcher> RevisionMatcher<M> {
+    pub fn new(fallback: M) -> RevisionMatcher<M> {
+        RevisionMatcher { fallback }
+    }
+}
+
+impl<M: JobMatcher> JobMatcher for RevisionMatcher<M> {
+    fn matches(&self, job: &Job, commit_ref: &CommitRef) -> bool {
+        if job.revisions.is_empty() {
+            return self.fallback.matches(job, commit_ref)
+        }
+
+        job.revisions.iter()
+            .any(|sha| sha.eq_ignore_ascii_case(&commit_ref.sha))
+    }
+
+    fn describe(&self, commit_ref: &CommitRef) -> String {
+        let (short_sha, _) = commit_ref.sha.split_at(commit_ref.sha.len().min(7));
+
+        format!("of {} or {}", short_sha, self.fallback.describe(commit_ref))
+    }
+}
+
+// Matches builds whose name is exactly the template with its placeholders
+// filled in, e.g. `{branch}-{sha:7}` or `PR-{number}`.
+pub struct TemplateMatcher {
+    source: String,
+    template: Template,
+}
+
+impl TemplateMatcher {
+    pub fn new(template: &str) -> Result<TemplateMatcher, Box<dyn Error>> {
+        Ok(
+            TemplateMatcher {
+                source: template.to_owned(),
+                template: Template::parse(template)?,
+            }
+        )
+    }
+
+    pub fn job_name(&self, commit_ref: &CommitRef) -> Option<String> {
+        self.template.expand(commit_ref)
+    }
+}
+
+impl JobMatcher for TemplateMatcher {
+    fn matches(&self, job: &Job, commit_ref: &CommitRef) -> bool {
+        self.job_name(commit_ref)
+            .is_some_and(|name| job.display_name == name)
+    }
+
+    fn describe(&self, commit_ref: &CommitRef) -> String {
+        format!(
+            "named `{}`",
+            self.job_name(commit_ref).unwrap_or_else(|| self.source.clone())
+        )
+    }
+}
+
+// Matches build names against a regular expression. The same placeholders as
+// in templates can be used and are replaced by their escaped values before
+// matching, e.g. `^{branch}-[0-9a-f]{5,}$`. Braces that don't form a known
+// placeholder are left to the regex syntax.
+pub struct RegexMatcher {
+    source: String,
+    template: Template,
+}
+
+impl RegexMatcher {
+    pub fn new(pattern: &str) -> Result<RegexMatcher, Box<dyn Error>> {
+        let template = Template {
+            parts: parse_template(pattern, false)?,
+        };
+
+        // Check the pattern is valid now rather than on the first build.
+        let sample: String = template.parts.iter()
+            .map(|part| match *part {
+                Part::Literal(ref s) => s.clone(),
+                _ => "x".to_owned(),
+            })
+            .collect();
+        Regex::new(&sample)
+            .map_err(|e| format!("invalid job name regex `{}`: {}", pattern, e))?;
+
+        Ok(
+            RegexMatcher {
+                source: pattern.to_owned(),
+                template,
+            }
+        )
+    }
+
+    fn regex(&self, commit_ref: &CommitRef) -> Option<Regex> {
+        let pattern = self.template.expand_with(commit_ref, regex::escape)?;
+
+        Regex::new(&pattern).ok()
+    }
+}
+
+impl JobMatcher for RegexMatcher {
+    fn matches(&self, job: &Job, commit_ref: &CommitRef) -> bool {
+        self.regex(commit_ref)
+            .is_some_and(|r| r.is_match(&job.display_name))
+    }
+
+    fn describe(&self, commit_ref: &CommitRef) -> String {
+        format!(
+            "matching `{}`",
+            self.regex(commit_ref)
+                .map_or_else(|| self.source.clone(), |r| r.as_str().to_owned())
+        )
+    }
+}
+
+// Text with `{owner}`, `{repo}`, `{branch}`, `{sha}`, `{sha:N}` and `{number}`
+// placeholders.
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Template, Box<dyn Error>> {
+        Ok(
+            Template {
+                parts: parse_template(template, true)?,
+            }
+        )
+    }
+
+    // Returns `None` when the template uses a value the commit doesn't have,
+    // like `{number}` for a commit that isn't part of a pull request.
+    pub fn expand(&self, commit_ref: &CommitRef) -> Option<String> {
+        self.expand_with(commit_ref, |value| value.to_owned())
+    }
+
+    // Like `expand`, but passes placeholder values through `escape` first.
+    pub fn expand_with<F>(&self, commit_ref: &CommitRef, escape: F) -> Option<String>
+    where F: Fn(&str) -> String {
+        let mut text = String::new();
+
+        for part in &self.parts {
+            match *part {
+                Part::Literal(ref s) => text.push_str(s),
+                _ => text.push_str(&escape(&part.expand(commit_ref)?)),
+            }
+        }
+
+        Some(text)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Part {
+    Literal(String),
+    Owner,
+    Repo,
+    Branch,
+    Sha(Option<usize>),
+    Number,
+}
+
+impl Part {
+    fn expand(&self, commit_ref: &CommitRef) -> Option<String> {
+        match *self {
+            Part::Literal(ref s) => Some(s.clone()),
+            Part::Owner => Some(commit_ref.owner.clone()),
+            Part::Repo => Some(commit_ref.repo.clone()),
+            Part::Branch => Some(commit_ref.branch.clone()),
+            Part::Sha(None) => Some(commit_ref.sha.clone()),
+            Part::Sha(Some(length)) => Some(
+                commit_ref.sha.chars().take(length).collect()
+            ),
+            Part::Number => commit_ref.number.map(|n| n.to_string()),
+        }
+    }
+}
+
+fn placeholder(name: &str) -> Option<Part> {
+    match name {
+        "owner" => Some(Part::Owner),
+        "repo" => Some(Part::Repo),
+        "branch" => Some(Part::Branch),
+        "sha" => Some(Part::Sha(None)),
+        "number" => Some(Part::Number),
+        _ if name.starts_with("sha:") => name[4..].parse()
+            .ok()
+            .filter(|&length| length > 0)
+            .map(|length| Part::Sha(Some(length))),
+        _ => None,
+    }
+}
+
+// Split a template into literal text and placeholders. In strict mode, any
+// `{...}` that isn't a known placeholder is an error.
+fn parse_template(template: &str, strict: bool) -> Result<Vec<Part>, Box<dyn Error>> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        literal.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let part = rest.find('}')
+            .and_then(|end| placeholder(&rest[1..end]).map(|p| (p, end)));
+
+        match part {
+            Some((part, end)) => {
+                if !literal.is_empty() {
+                    parts.push(Part::Literal(literal.clone()));
+                    literal.clear();
+                }
+
+                parts.push(part);
+                rest = &rest[end + 1..];
+            },
+            None if strict => {
+                return Err(
+                    format!(
+                        "unknown placeholder in template `{}`",
+                        template
+                    ).into()
+                )
+            },
+            None => {
+                literal.push('{');
+                rest = &rest[1..];
+            },
+        }
+    }
+
+    literal.push_str(rest);
+    if !literal.is_empty() {
+        parts.push(Part::Literal(literal));
+    }
+
+    Ok(parts)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use jenkins::JobStatus;
+
+    use super::*;
+
+    fn commit_ref() -> CommitRef {
+        CommitRef {
+            number: Some(1347),
+            ..CommitRef::test_default()
+        }
+    }
+
+    fn job(display_name: &str) -> Job {
+        Job {
+            display_name: display_name.to_owned(),
+            result: JobStatus::Pending,
+            ..Job::default()
+        }
+    }
+
+    #[test]
+    fn revision_matcher_compares_full_sha() {
+        let matcher = RevisionMatcher::new(Af83);
+
+        let mut built = job("renamed-by-hand");
+        built.revisions = vec![
+            "a3c1f0e9d8c7b6a5f4e3d2c1b0a9f8e7d6c5b4a3".to_owned(),
+            "6DCB09B5B57875F334F61AEBED695E2E4193DB5E".to_owned(),
+        ];
+        assert!(matcher.matches(&built, &commit_ref()));
+
+        // Same branch and SHA prefix, but a different commit.
+        let mut other = job("new-topic-6dcb0");
+        other.revisions = vec![
+            "6dcb0aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa".to_owned(),
+        ];
+        assert!(!matcher.matches(&other, &commit_ref()));
+    }
+
+    #[test]
+    fn revision_matcher_falls_back_to_job_name() {
+        let matcher = RevisionMatcher::new(Af83);
+
+        assert!(matcher.matches(&job("new-topic-6dcb0"), &commit_ref()));
+        assert!(!matcher.matches(&job("new-topic-6dcb1"), &commit_ref()));
+        assert_eq!(
+            matcher.describe(&commit_ref()),
+            "of 6dcb09b or named `new-topic-6dcb0`"
+        );
+    }
+
+    #[test]
+    fn template_matcher_fills_in_placeholders() {
+        let matcher = TemplateMatcher::new("{branch}-{sha:7}")
+            .expect("Failed to parse template");
+
+        assert_eq!(
+            matcher.job_name(&commit_ref()),
+            Some("new-topic-6dcb09b".to_owned())
+        );
+        assert!(matcher.matches(&job("new-topic-6dcb09b"), &commit_ref()));
+        assert!(!matcher.matches(&job("new-topic-6dcb0"), &commit_ref()));
+    }
+
+    #[test]
+    fn template_matcher_matches_pull_request_number() {
+        let matcher = TemplateMatcher::new("PR-{number}")
+            .expect("Failed to parse template");
+
+        assert!(matcher.matches(&job("PR-1347"), &commit_ref()));
+
+        let mut without_number = commit_ref();
+        without_number.number = None;
+
+        assert!(!matcher.matches(&job("PR-"), &without_number));
+    }
+
+    #[test]
+    fn template_matcher_rejects_unknown_placeholders() {
+        assert!(TemplateMatcher::new("{branch}-{commit}").is_err());
+        assert!(TemplateMatcher::new("{sha:0}").is_err());
+    }
+
+    #[test]
+    fn regex_matcher_escapes_placeholder_values() {
+        let matcher = RegexMatcher::new(r"^{branch}-[0-9a-f]{5,}$")
+            .expect("Failed to parse regex");
+
+        let mut dotted = commit_ref();
+        dotted.branch = "release-1.2".to_owned();
+
+        assert!(matcher.matches(&job("release-1.2-6dcb09b"), &dotted));
+        assert!(!matcher.matches(&job("release-102-6dcb09b"), &dotted));
+        assert!(!matcher.matches(&job("release-1.2-6dcb"), &dotted));
+        assert_eq!(
+            matcher.describe(&dotted),
+            r"matching `^release\-1\.2-[0-9a-f]{5,}$`"
+        );
+    }
+
+    #[test]
+    fn regex_matcher_rejects_invalid_patterns() {
+        assert!(RegexMatcher::new("^{branch}-(").is_err());
+    }
+}
@@ -17,9 +17,23 @@
 
 #[macro_use]
 extern crate log;
+#[macro_use]
+extern crate rouille;
+#[macro_use]
+extern crate serde_derive;
 
+pub mod config;
 pub mod jenkins;
+pub mod job_locator;
+pub mod job_matcher;
 pub mod pull_request;
+pub mod reporter;
+pub mod scheduler;
+pub mod server;
+pub mod store;
+pub mod tracker;
 
 mod github;
+mod gitlab;
+mod http;
 mod af83;
@@ -16,56 +16,244 @@
 // along with Kipper. If not, see <http://www.gnu.org/licenses/>.
 
 extern crate getopts;
-extern crate json;
 #[macro_use]
 extern crate log;
-extern crate stderrlog;
-#[macro_use]
 extern crate rouille;
+extern crate stderrlog;
 
 extern crate kipper;
 
+use std::collections::BTreeMap;
 use std::env;
-use std::thread;
-use std::io::Read;
-use std::time::Duration;
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::sync::Arc;
 
-use getopts::Options;
+use getopts::{Matches, Options};
 
-use kipper::jenkins;
-use kipper::pull_request::{CommitRef, pull_request_opened_or_synchronized};
-
-const DEFAULT_PORT: u16 = 8000;
+use kipper::config::{
+    Config,
+    GitHubSettings,
+    GitLabSettings,
+    JenkinsSettings,
+    Settings,
+};
+use kipper::server;
+use kipper::store::TrackerStore;
+use kipper::scheduler::Scheduler;
+use kipper::tracker::Registry;
 
 fn print_usage(opts: Options) {
-    let brief = "Usage: kipper --jenkins-url 'https://jenkins.example.com' --jenkins-user-id username --jenkins-token a72a57d448694703b2c3fd19e666ecc5 --github-token 1dc41fad0516460b870014b25b11847d";
-    print!("{}", opts.usage(&brief));
+    let brief = "Usage: kipper [check-config] [--config kipper.toml] [options]
+
+Settings are read from command line flags, then `KIPPER_*` environment
+variables, then the configuration file, in that order of precedence.";
+    print!("{}", opts.usage(brief));
+}
+
+fn settings_from_opts(opt_matches: &Matches) -> Result<Settings, Box<dyn Error>> {
+    let port = match opt_matches.opt_str("p") {
+        Some(p) => Some(
+            p.parse()
+                .map_err(|e| format!("Unable to parse specified port: {}", e))?
+        ),
+        None => None,
+    };
+
+    let settings = Settings {
+        port,
+        state_file: opt_matches.opt_str("state-file").map(PathBuf::from),
+        workers: parse_opt(opt_matches, "workers")?,
+        queue_limit: parse_opt(opt_matches, "queue-limit")?,
+        jenkins: JenkinsSettings {
+            url: opt_matches.opt_str("jenkins-url"),
+            user_id: opt_matches.opt_str("jenkins-user-id"),
+            token: opt_matches.opt_str("jenkins-token"),
+            token_file: opt_matches.opt_str("jenkins-token-file")
+                .map(PathBuf::from),
+            notification_token: opt_matches.opt_str("jenkins-notification-token"),
+            notification_token_file: opt_matches
+                .opt_str("jenkins-notification-token-file")
+                .map(PathBuf::from),
+            max_concurrent_requests: parse_opt(
+                opt_matches,
+                "jenkins-max-concurrent-requests"
+            )?,
+        },
+        github: GitHubSettings {
+            api_url: opt_matches.opt_str("github-api-url"),
+            token: opt_matches.opt_str("github-token"),
+            token_file: opt_matches.opt_str("github-token-file")
+                .map(PathBuf::from),
+            app_id: match opt_matches.opt_str("github-app-id") {
+                Some(id) => Some(
+                    id.parse()
+                        .map_err(|e| format!("--github-app-id: {}", e))?
+                ),
+                None => None,
+            },
+            private_key: None,
+            private_key_file: opt_matches.opt_str("github-private-key-file")
+                .map(PathBuf::from),
+            webhook_secret: opt_matches.opt_str("github-webhook-secret"),
+            webhook_secret_file: opt_matches.opt_str("github-webhook-secret-file")
+                .map(PathBuf::from),
+            report_superseded: if opt_matches.opt_present("github-report-superseded") {
+                Some(true)
+            } else {
+                None
+            },
+        },
+        gitlab: GitLabSettings {
+            url: opt_matches.opt_str("gitlab-url"),
+            token: opt_matches.opt_str("gitlab-token"),
+            token_file: opt_matches.opt_str("gitlab-token-file")
+                .map(PathBuf::from),
+            webhook_secret: opt_matches.opt_str("gitlab-webhook-secret"),
+            webhook_secret_file: opt_matches.opt_str("gitlab-webhook-secret-file")
+                .map(PathBuf::from),
+        },
+        repos: BTreeMap::new(),
+    };
+
+    settings.read_secret_files()
+}
+
+fn parse_opt<T>(opt_matches: &Matches, name: &str) -> Result<Option<T>, Box<dyn Error>>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match opt_matches.opt_str(name) {
+        Some(value) => Ok(
+            Some(
+                value.parse()
+                    .map_err(|e| format!("--{}: {}", name, e))?
+            )
+        ),
+        None => Ok(None),
+    }
 }
 
-fn internal_server_error() -> rouille::Response {
-    rouille::Response::text("500 Internal Server Error")
-        .with_status_code(500)
+fn load_config(opt_matches: &Matches) -> Result<Config, Box<dyn Error>> {
+    let cli_settings = settings_from_opts(opt_matches)?;
+    let env_settings = Settings::from_env(|v| env::var(v).ok())?;
+
+    let config_path = opt_matches.opt_str("config")
+        .or_else(|| env::var("KIPPER_CONFIG").ok());
+    let file_settings = match config_path {
+        Some(path) => Settings::from_file(&PathBuf::from(path))?,
+        None => Settings::default(),
+    };
+
+    Config::from_settings(
+        cli_settings
+            .merge(env_settings)
+            .merge(file_settings)
+    )
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
     let mut opts = Options::new();
+    opts.optopt("c", "config", "configuration file", "kipper.toml");
     opts.optopt("", "jenkins-url", "Jenkins URL (required)", "https://jenkins.example.com");
     opts.optopt("", "jenkins-user-id", "Jenkins user ID (required)", "USER_ID");
     opts.optopt("", "jenkins-token", "Jenkins API token (required)", "TOKEN");
+    opts.optopt("", "jenkins-token-file", "file containing the Jenkins API token", "PATH");
+    opts.optopt(
+        "",
+        "jenkins-notification-token",
+        "token Jenkins build notifications must include",
+        "TOKEN"
+    );
+    opts.optopt(
+        "",
+        "jenkins-notification-token-file",
+        "file containing the Jenkins notification token",
+        "PATH"
+    );
+    opts.optopt(
+        "",
+        "github-api-url",
+        "GitHub API URL, for GitHub Enterprise Server",
+        "https://ghe.example.com/api/v3"
+    );
     opts.optopt(
         "",
         "github-token",
-        "GitHub API token with \"repo:status\" permission (required)",
+        "GitHub API token with \"repo:status\" permission",
         "TOKEN"
     );
+    opts.optopt("", "github-token-file", "file containing the GitHub API token", "PATH");
+    opts.optopt("", "github-app-id", "ID of the GitHub App to authenticate as", "ID");
+    opts.optopt(
+        "",
+        "github-private-key-file",
+        "PEM file containing the GitHub App's private key",
+        "PATH"
+    );
+    opts.optopt(
+        "",
+        "github-webhook-secret",
+        "secret used to verify GitHub webhook signatures",
+        "SECRET"
+    );
+    opts.optopt(
+        "",
+        "github-webhook-secret-file",
+        "file containing the GitHub webhook secret",
+        "PATH"
+    );
+    opts.optflag(
+        "",
+        "github-report-superseded",
+        "post an error status on commits superseded by a newer push"
+    );
+    opts.optopt("", "gitlab-url", "GitLab URL", "https://gitlab.example.com");
+    opts.optopt("", "gitlab-token", "GitLab API token with \"api\" scope", "TOKEN");
+    opts.optopt("", "gitlab-token-file", "file containing the GitLab API token", "PATH");
+    opts.optopt(
+        "",
+        "gitlab-webhook-secret",
+        "secret token GitLab sends with webhooks",
+        "SECRET"
+    );
+    opts.optopt(
+        "",
+        "gitlab-webhook-secret-file",
+        "file containing the GitLab webhook secret",
+        "PATH"
+    );
+    opts.optopt(
+        "",
+        "state-file",
+        "journal used to resume build tracking after a restart",
+        "PATH"
+    );
+    opts.optopt(
+        "",
+        "jenkins-max-concurrent-requests",
+        "limit on simultaneous requests to Jenkins (default 4)",
+        "N"
+    );
+    opts.optopt("", "workers", "threads polling Jenkins builds (default 8)", "N");
+    opts.optopt(
+        "",
+        "queue-limit",
+        "trackers to run before refusing webhooks (default 1000)",
+        "N"
+    );
     opts.optopt("p", "port", "set port number", "PORT");
     opts.optflag("h", "help", "print this help menu");
 
     let opt_matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
-        Err(e) => panic!(e.to_string()),
+        Err(e) => panic!("{}", e.to_string()),
     };
 
     if opt_matches.opt_present("h") {
@@ -73,42 +261,38 @@ fn main() {
         return;
     }
 
-    let jenkins_url = match opt_matches.opt_str("jenkins-url") {
-        Some(url) => url,
-        None => {
-            print_usage(opts);
-            return;
-        },
-    };
-
-    let jenkins_user_id = match opt_matches.opt_str("jenkins-user-id") {
-        Some(user_id) => user_id,
-        None => {
+    let check_config = match opt_matches.free.first() {
+        Some(command) if command == "check-config" => true,
+        Some(command) => {
+            eprintln!("kipper: unknown command '{}'", command);
             print_usage(opts);
-            return;
+            process::exit(1);
         },
+        None => false,
     };
 
-    let jenkins_token = match opt_matches.opt_str("jenkins-token") {
-        Some(token) => token,
-        None => {
-            print_usage(opts);
-            return;
+    let config = match load_config(&opt_matches) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("kipper: {}", e);
+            process::exit(1);
         },
     };
 
-    let github_token = match opt_matches.opt_str("github-token") {
-        Some(token) => token,
-        None => {
-            print_usage(opts);
-            return;
-        },
-    };
+    if check_config {
+        match config.to_redacted_toml() {
+            Ok(toml) => {
+                println!("Configuration OK\n");
+                print!("{}", toml);
 
-    let port = match opt_matches.opt_str("p") {
-        Some(p) => p.parse().expect("Unable to parse specified port"),
-        None => DEFAULT_PORT,
-    };
+                return;
+            },
+            Err(e) => {
+                eprintln!("kipper: {}", e);
+                process::exit(1);
+            },
+        }
+    }
 
     // Logging
     stderrlog::new()
@@ -118,72 +302,54 @@ fn main() {
         .init()
         .expect("Logger failed to initialise");
 
-    println!("Server listening on 0.0.0.0:{}", port);
+    if config.github.webhook_secret.is_none() {
+        warn!("No GitHub webhook secret given, webhook signatures will not be verified");
+    }
 
-    rouille::start_server(format!("0.0.0.0:{}", port), move |request| {
-        router!(request,
-            (POST) (/github/pull_request_event) => {
-                match request.data() {
-                    None => rouille::Response::text("400 Bad Request")
-                        .with_status_code(400),
-                    Some(mut data) => {
-                        let mut body = String::new();
-                        try_or_400!(data.read_to_string(&mut body));
-
-                        let json = match json::parse(body.as_ref()) {
-                            Ok(j) => j,
-                            Err(e) => {
-                                error!("{}", e.to_string());
-
-                                return internal_server_error()
-                            },
-                        };
-
-                        if !pull_request_opened_or_synchronized(json.clone()) {
-                            return rouille::Response::text("No status update needed.")
-                                .with_status_code(200)
-                        }
-
-                        let commit_ref = match CommitRef::new(json) {
-                            Ok(cr) => cr,
-                            Err(e) => {
-                                error!("{}", e.to_string());
-
-                                return internal_server_error()
-                            },
-                        };
-
-                        // Clone so we can use these values in the thread
-                        // closure. Since both closures are required to be
-                        // 'static, we can't use references to these values.
-                        let jenkins_url = jenkins_url.clone();
-                        let jenkins_user_id = jenkins_user_id.clone();
-                        let jenkins_token = jenkins_token.clone();
-                        let github_token = github_token.clone();
-
-                        thread::spawn(move || {
-                            thread::sleep(Duration::from_secs(30));
-
-                            match jenkins::find_and_track_build_and_update_status(
-                                commit_ref,
-                                jenkins_url,
-                                &jenkins_user_id,
-                                &jenkins_token,
-                                github_token,
-                            ) {
-                                Ok(_) => {},
-                                Err(e) => error!("{}", e.to_string()),
-                            };
-                        });
-
-                        rouille::Response::text("202 Accepted")
-                            .with_status_code(202)
-                    }
-                }
+    if config.jenkins.notification_token.is_none() {
+        warn!("No Jenkins notification token given, build notifications are disabled");
+    }
+
+    if let Some(ref gitlab) = config.gitlab {
+        if gitlab.webhook_secret.is_none() {
+            warn!("No GitLab webhook secret given, webhook tokens will not be verified");
+        }
+    }
+
+    let store = match config.state_file {
+        Some(ref path) => match TrackerStore::open(path) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("{}: {}", path.display(), e);
+                process::exit(1);
             },
+        },
+        None => {
+            warn!("No state file given, build tracking will not survive a restart");
+
+            TrackerStore::in_memory()
+        },
+    };
+    let store = Arc::new(store);
+    let registry = Arc::new(Registry::new());
+
+    let scheduler = Arc::new(
+        Scheduler::new(config.clone(), store.clone(), registry.clone())
+    );
+    scheduler.resume();
+    scheduler.start();
 
-            _ => rouille::Response::text("404 Not Found")
-                    .with_status_code(404)
-        )
+    let port = config.port;
+    let context = server::Context {
+        config,
+        store,
+        registry,
+        scheduler,
+    };
+
+    println!("Server listening on 0.0.0.0:{}", port);
+
+    rouille::start_server(format!("0.0.0.0:{}", port), move |request| {
+        server::handle(&context, request)
     });
 }
@@ -15,43 +15,488 @@
 // You should have received a copy of the GNU General Public License
 // along with Kipper. If not, see <http://www.gnu.org/licenses/>.
 
-extern crate json;
+extern crate serde_json;
 
 use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
 
-#[derive(Debug)]
+use config::RepoConfig;
+
+// Where a repository is hosted, and so where to report its builds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Forge {
+    GitHub,
+    GitLab,
+}
+
+impl fmt::Display for Forge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Forge::GitHub => write!(f, "github"),
+            Forge::GitLab => write!(f, "gitlab"),
+        }
+    }
+}
+
+impl FromStr for Forge {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Forge, String> {
+        match s {
+            "github" => Ok(Forge::GitHub),
+            "gitlab" => Ok(Forge::GitLab),
+            _ => Err(format!("unknown forge '{}'", s)),
+        }
+    }
+}
+
+// A GitHub `pull_request` webhook event. Fields Kipper needs are required,
+// so that an incomplete payload is refused rather than tracked with empty
+// names.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PullRequestEvent {
+    pub action: String,
+    pub number: u64,
+    pub pull_request: PullRequest,
+
+    // The label added or removed by `labeled` and `unlabeled` events.
+    pub label: Option<Label>,
+
+    pub installation: Option<Installation>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PullRequest {
+    pub title: String,
+    pub html_url: String,
+    pub user: User,
+
+    #[serde(default)]
+    pub draft: bool,
+
+    #[serde(default)]
+    pub labels: Vec<Label>,
+
+    // The author's relationship to the base repository, like "MEMBER".
+    pub author_association: Option<String>,
+
+    pub head: GitRef,
+    pub base: GitRef,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct GitRef {
+    #[serde(rename = "ref")]
+    pub branch: String,
+    pub sha: String,
+
+    // Null when the head repository has been deleted.
+    pub repo: Option<Repository>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Repository {
+    pub name: String,
+    pub full_name: String,
+    pub owner: User,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct User {
+    pub login: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Label {
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Installation {
+    pub id: u64,
+}
+
+impl PullRequestEvent {
+    pub fn from_json(body: &str) -> Result<PullRequestEvent, Box<dyn Error>> {
+        serde_json::from_str(body)
+            .map_err(|e| format!("invalid pull request event: {}", e).into())
+    }
+}
+
+// A GitHub `push` webhook event.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PushEvent {
+    // The full name of the pushed ref, like "refs/heads/master".
+    #[serde(rename = "ref")]
+    pub git_ref: String,
+
+    // The SHA the ref points to after the push, all zeros when it was
+    // deleted.
+    pub after: String,
+
+    #[serde(default)]
+    pub deleted: bool,
+
+    pub repository: Repository,
+    pub sender: Option<User>,
+    pub installation: Option<Installation>,
+}
+
+impl PushEvent {
+    pub fn from_json(body: &str) -> Result<PushEvent, Box<dyn Error>> {
+        serde_json::from_str(body)
+            .map_err(|e| format!("invalid push event: {}", e).into())
+    }
+
+    pub fn deleted(&self) -> bool {
+        self.deleted || self.after.chars().all(|c| c == '0')
+    }
+}
+
+// A GitHub `check_run` webhook event, sent to the GitHub App that created the
+// check run.
+#[derive(Clone, Debug, Deserialize)]
+pub struct CheckRunEvent {
+    pub action: String,
+    pub check_run: CheckRun,
+    pub repository: Repository,
+    pub installation: Option<Installation>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CheckRun {
+    pub head_sha: String,
+    pub check_suite: CheckSuite,
+
+    // The pull requests whose head is `head_sha`. Their repositories only
+    // have an ID and a URL, so just the number and base branch are read.
+    #[serde(default)]
+    pub pull_requests: Vec<CheckRunPullRequest>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CheckSuite {
+    pub head_branch: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CheckRunPullRequest {
+    pub number: u64,
+    pub base: CheckRunBase,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CheckRunBase {
+    #[serde(rename = "ref")]
+    pub branch: String,
+}
+
+impl CheckRunEvent {
+    pub fn from_json(body: &str) -> Result<CheckRunEvent, Box<dyn Error>> {
+        serde_json::from_str(body)
+            .map_err(|e| format!("invalid check run event: {}", e).into())
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct CommitRef {
+    // The repository the pull request was opened on, where its builds are
+    // found and reported.
     pub owner: String,
     pub repo: String,
+
+    // The `owner/name` of the repository the commit was pushed to, if it's
+    // a fork.
+    pub head_repo: Option<String>,
+
     pub sha: String,
     pub branch: String,
+
+    // The branch the pull request would be merged into.
+    pub base_branch: Option<String>,
+
+    // The pull request the commit belongs to.
+    pub number: Option<u64>,
+
+    // The pull request's author, title and labels, when the commit was
+    // pushed.
+    pub author: Option<String>,
+    pub title: Option<String>,
+    pub labels: Vec<String>,
+
+    pub forge: Forge,
+
+    // The GitHub App installation that sent the webhook, if any.
+    pub installation_id: Option<u64>,
 }
 
 impl CommitRef {
-    pub fn new(
-        mut github_push_event: json::JsonValue
-    ) -> Result<CommitRef, Box<Error>> {
+    pub fn new(event: &PullRequestEvent) -> Result<CommitRef, Box<dyn Error>> {
+        let pull_request = &event.pull_request;
+        let base_repo = pull_request.base.repo.as_ref()
+            .ok_or("pull request event has no `pull_request.base.repo`")?;
+        let head_repo = pull_request.head.repo.as_ref()
+            .map(|repo| repo.full_name.clone())
+            .filter(|name| *name != base_repo.full_name);
+
+        Ok(
+            CommitRef {
+                owner: base_repo.owner.login.clone(),
+                repo: base_repo.name.clone(),
+                head_repo,
+                sha: pull_request.head.sha.clone(),
+                branch: pull_request.head.branch.clone(),
+                base_branch: Some(pull_request.base.branch.clone()),
+                number: Some(event.number),
+                author: Some(pull_request.user.login.clone()),
+                title: Some(pull_request.title.clone()),
+                labels: pull_request.labels.iter()
+                    .map(|label| label.name.clone())
+                    .collect(),
+                forge: Forge::GitHub,
+                installation_id: event.installation.as_ref()
+                    .map(|installation| installation.id),
+            }
+        )
+    }
+
+    // The commit a branch or tag was pushed to. Pushes don't belong to a
+    // pull request, so the tag name stands in for the branch.
+    pub fn from_push(push_event: &PushEvent) -> Result<CommitRef, Box<dyn Error>> {
+        let git_ref = &push_event.git_ref;
+        let branch = git_ref.strip_prefix("refs/heads/")
+            .or_else(|| git_ref.strip_prefix("refs/tags/"))
+            .unwrap_or(git_ref);
+
+        if branch.is_empty() || push_event.after.is_empty() {
+            return Err(
+                format!("push event has no branch or commit for '{}'", git_ref)
+                    .into()
+            )
+        }
+
         Ok(
             CommitRef {
-                owner: github_push_event["pull_request"]["head"]["repo"]["owner"]["login"].take_string().unwrap_or_default(),
-                repo: github_push_event["pull_request"]["head"]["repo"]["name"].take_string().unwrap_or_default(),
-                sha: github_push_event["pull_request"]["head"]["sha"].take_string().unwrap_or_default(),
-                branch: github_push_event["pull_request"]["head"]["ref"].take_string().unwrap_or_default(),
+                owner: push_event.repository.owner.login.clone(),
+                repo: push_event.repository.name.clone(),
+                head_repo: None,
+                sha: push_event.after.clone(),
+                branch: branch.to_owned(),
+                base_branch: None,
+                number: None,
+                author: push_event.sender.as_ref().map(|user| user.login.clone()),
+                title: None,
+                labels: Vec::new(),
+                forge: Forge::GitHub,
+                installation_id: push_event.installation.as_ref()
+                    .map(|installation| installation.id),
             }
         )
     }
+
+    pub fn from_check_run(
+        check_run_event: &CheckRunEvent
+    ) -> Result<CommitRef, Box<dyn Error>> {
+        let check_run = &check_run_event.check_run;
+        let branch = check_run.check_suite.head_branch.as_ref()
+            .ok_or("check run event has no `check_run.check_suite.head_branch`")?;
+        let pull_request = check_run.pull_requests.first();
+
+        Ok(
+            CommitRef {
+                owner: check_run_event.repository.owner.login.clone(),
+                repo: check_run_event.repository.name.clone(),
+                head_repo: None,
+                sha: check_run.head_sha.clone(),
+                branch: branch.clone(),
+                base_branch: pull_request.map(|pr| pr.base.branch.clone()),
+                number: pull_request.map(|pr| pr.number),
+                author: None,
+                title: None,
+                labels: Vec::new(),
+                forge: Forge::GitHub,
+                installation_id: check_run_event.installation.as_ref()
+                    .map(|installation| installation.id),
+            }
+        )
+    }
+
+    // A GitHub commit outside of a pull request, for tests to fill in the
+    // fields they care about.
+    #[cfg(test)]
+    pub fn test_default() -> CommitRef {
+        CommitRef {
+            owner: "octocat".to_owned(),
+            repo: "Hello-World".to_owned(),
+            head_repo: None,
+            sha: "6dcb09b5b57875f334f61aebed695e2e4193db5e".to_owned(),
+            branch: "new-topic".to_owned(),
+            base_branch: None,
+            number: None,
+            author: None,
+            title: None,
+            labels: Vec::new(),
+            forge: Forge::GitHub,
+            installation_id: None,
+        }
+    }
 }
 
-pub fn pull_request_opened_or_synchronized(
-    mut github_push_event: json::JsonValue
-) -> bool {
-    let action = github_push_event["action"].take_string().unwrap_or_default();
+// What to do with pull requests from forks opened by people without write
+// access to the repository, whose builds might run untrusted code.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ForkPolicy {
+    #[default]
+    Track,
+    Skip,
 
-    if action == "opened" || action == "synchronize" {
-        return true
+    // Track once a maintainer adds the repository's `fork_label`.
+    Label,
+}
+
+impl FromStr for ForkPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ForkPolicy, String> {
+        match s {
+            "track" => Ok(ForkPolicy::Track),
+            "skip" => Ok(ForkPolicy::Skip),
+            "label" => Ok(ForkPolicy::Label),
+            _ => Err(
+                format!(
+                    "unknown fork policy '{}', expected 'track', 'skip' or 'label'",
+                    s
+                )
+            ),
+        }
     }
+}
 
-    false
+// Authors with write access to the base repository.
+const TRUSTED_ASSOCIATIONS: [&str; 3] = ["OWNER", "MEMBER", "COLLABORATOR"];
+
+pub fn from_untrusted_fork(event: &PullRequestEvent) -> bool {
+    let pull_request = &event.pull_request;
+
+    let same_repo = match (
+        pull_request.head.repo.as_ref(),
+        pull_request.base.repo.as_ref(),
+    ) {
+        (Some(head), Some(base)) => head.full_name == base.full_name,
+
+        // The head repository of a deleted fork is null.
+        _ => false,
+    };
+
+    if same_repo {
+        return false
+    }
+
+    !pull_request.author_association.as_ref()
+        .is_some_and(|association| {
+            TRUSTED_ASSOCIATIONS.contains(&association.as_str())
+        })
+}
+
+pub fn has_label(event: &PullRequestEvent, label: &str) -> bool {
+    event.pull_request.labels.iter()
+        .any(|l| l.name == label)
+}
+
+// Whether `label` was just added to the pull request.
+pub fn labeled_with(event: &PullRequestEvent, label: &str) -> bool {
+    event.action == "labeled"
+        && event.label.as_ref().is_some_and(|l| l.name == label)
+}
+
+// What a pull request event asks Kipper to do.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Dispatch {
+    // Follow the build of the head commit.
+    Track,
+
+    // Stop following the pull request's builds.
+    Stop,
+
+    // Nothing to do, and why.
+    Ignore(String),
+}
+
+pub fn dispatch(event: &PullRequestEvent, repo: &RepoConfig) -> Dispatch {
+    let pull_request = &event.pull_request;
+    let untrusted = from_untrusted_fork(event);
+
+    // Forks waiting for a label are tracked from the moment it's added.
+    let fork_label = match repo.untrusted_forks {
+        ForkPolicy::Label if untrusted => repo.fork_label.as_ref(),
+        _ => None,
+    };
+
+    match event.action.as_str() {
+        "closed" => return Dispatch::Stop,
+        "opened" | "synchronize" | "reopened" | "ready_for_review" => {},
+        "labeled" if repo.run_labels.iter()
+            .chain(fork_label)
+            .any(|label| labeled_with(event, label)) => {},
+        _ => return Dispatch::Ignore("No status update needed.".to_owned()),
+    }
+
+    if repo.skip_drafts && pull_request.draft {
+        return Dispatch::Ignore(
+            "Waiting for the pull request to be ready for review.".to_owned()
+        )
+    }
+
+    if untrusted {
+        match repo.untrusted_forks {
+            ForkPolicy::Track => {},
+            ForkPolicy::Skip => return Dispatch::Ignore(
+                "Not tracking pull requests from untrusted forks.".to_owned()
+            ),
+            ForkPolicy::Label => {
+                if let Some(label) = fork_label {
+                    if !has_label(event, label) {
+                        return Dispatch::Ignore(
+                            format!("Waiting for the \"{}\" label.", label)
+                        )
+                    }
+                }
+            },
+        }
+    }
+
+    Dispatch::Track
+}
+
+// Re-running a check run from GitHub follows its build again.
+pub fn dispatch_check_run(event: &CheckRunEvent) -> Dispatch {
+    if event.action == "rerequested" {
+        return Dispatch::Track
+    }
+
+    Dispatch::Ignore("No status update needed.".to_owned())
+}
+
+pub fn dispatch_push(event: &PushEvent, repo: &RepoConfig) -> Dispatch {
+    if event.deleted() {
+        return Dispatch::Ignore("Branch deleted.".to_owned())
+    }
+
+    if event.git_ref.starts_with("refs/tags/") {
+        if repo.track_tags {
+            return Dispatch::Track
+        }
+
+        return Dispatch::Ignore("Not tracking tags.".to_owned())
+    }
+
+    if !event.git_ref.starts_with("refs/heads/") {
+        return Dispatch::Ignore("No status update needed.".to_owned())
+    }
+
+    Dispatch::Track
 }
 
 
@@ -477,63 +922,411 @@ mod tests {
           }
         }"#;
 
-        let json = json::parse(payload)
+        let event = PullRequestEvent::from_json(payload)
             .expect("Failed to parse payload.");
 
-        let commit_ref = CommitRef::new(json)
+        let commit_ref = CommitRef::new(&event)
             .expect("Failed to create CommitRef from payload");
 
         assert_eq!(commit_ref.owner, "baxterthehacker");
         assert_eq!(commit_ref.repo, "public-repo");
         assert_eq!(commit_ref.sha, "0d1a26e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c");
         assert_eq!(commit_ref.branch, "changes");
+        assert_eq!(commit_ref.base_branch, Some("master".to_owned()));
+        assert_eq!(commit_ref.number, Some(1));
+        assert_eq!(commit_ref.author, Some("baxterthehacker".to_owned()));
+        assert_eq!(
+            commit_ref.title,
+            Some("Update the README with new information".to_owned())
+        );
+        assert_eq!(commit_ref.installation_id, Some(234));
+
+        assert_eq!(event.pull_request.base.sha, "9049f1265b7d61be4a8904a9a27120d2064dab3b");
+        assert_eq!(
+            event.pull_request.html_url,
+            "https://github.com/baxterthehacker/public-repo/pull/1"
+        );
+        assert!(!event.pull_request.draft);
+    }
+
+    fn event_payload(action: &str, author_association: &str) -> String {
+        format!(r#"{{
+            "action": "{}",
+            "number": 12,
+            "label": {{ "name": "safe to test" }},
+            "pull_request": {{
+                "title": "Fix typo",
+                "html_url": "https://github.com/octocat/Hello-World/pull/12",
+                "user": {{ "login": "contributor" }},
+                "draft": true,
+                "author_association": "{}",
+                "labels": [{{ "name": "safe to test" }}],
+                "head": {{
+                    "ref": "fix-typo",
+                    "sha": "9c48853fa3dc5c1c3d6f1f1cd1f2743e72652840",
+                    "repo": {{
+                        "name": "Hello-World",
+                        "full_name": "contributor/Hello-World",
+                        "owner": {{ "login": "contributor" }}
+                    }}
+                }},
+                "base": {{
+                    "ref": "master",
+                    "sha": "553c2077f0edc3d5dc5d17262f6aa498e69d6f8e",
+                    "repo": {{
+                        "name": "Hello-World",
+                        "full_name": "octocat/Hello-World",
+                        "owner": {{ "login": "octocat" }}
+                    }}
+                }}
+            }}
+        }}"#, action, author_association)
+    }
+
+    fn event(action: &str) -> PullRequestEvent {
+        PullRequestEvent::from_json(&event_payload(action, "NONE"))
+            .expect("Failed to parse payload.")
+    }
+
+    fn fork_payload(author_association: &str) -> PullRequestEvent {
+        PullRequestEvent::from_json(&event_payload("opened", author_association))
+            .expect("Failed to parse payload.")
     }
 
     #[test]
-    fn pull_request_opened_or_synchronized_returns_true_when_opened() {
-        let payload = r#"{
-            "action": "opened"
-        }"#;
+    fn pull_request_event_requires_head_sha() {
+        let payload = event_payload("opened", "NONE")
+            .replace(r#""sha": "9c48853fa3dc5c1c3d6f1f1cd1f2743e72652840","#, "");
 
-        let json = json::parse(payload)
-            .expect("Failed to parse payload.");
+        let error = PullRequestEvent::from_json(&payload)
+            .expect_err("Event should be invalid");
 
-        assert_eq!(
-            pull_request_opened_or_synchronized(json),
-            true
+        assert!(error.to_string().contains("missing field `sha`"));
+    }
+
+    #[test]
+    fn commit_ref_requires_base_repository() {
+        let mut event = event("opened");
+        event.pull_request.base.repo = None;
+
+        assert!(CommitRef::new(&event).is_err());
+    }
+
+    #[test]
+    fn commit_ref_from_fork_uses_base_repository() {
+        let commit_ref = CommitRef::new(&fork_payload("CONTRIBUTOR"))
+            .expect("Failed to create CommitRef from payload");
+
+        assert_eq!(commit_ref.owner, "octocat");
+        assert_eq!(commit_ref.repo, "Hello-World");
+        assert_eq!(commit_ref.head_repo, Some("contributor/Hello-World".to_owned()));
+        assert_eq!(commit_ref.branch, "fix-typo");
+        assert_eq!(commit_ref.labels, vec!["safe to test".to_owned()]);
+    }
+
+    #[test]
+    fn from_untrusted_fork_checks_author_association() {
+        assert!(from_untrusted_fork(&fork_payload("CONTRIBUTOR")));
+        assert!(from_untrusted_fork(&fork_payload("FIRST_TIME_CONTRIBUTOR")));
+        assert!(!from_untrusted_fork(&fork_payload("MEMBER")));
+    }
+
+    #[test]
+    fn from_untrusted_fork_returns_false_for_same_repository() {
+        let mut event = fork_payload("NONE");
+        if let Some(ref mut repo) = event.pull_request.head.repo {
+            repo.full_name = "octocat/Hello-World".to_owned();
+        }
+
+        assert!(!from_untrusted_fork(&event));
+    }
+
+    #[test]
+    fn from_untrusted_fork_returns_true_for_deleted_fork() {
+        let mut event = fork_payload("NONE");
+        event.pull_request.head.repo = None;
+
+        assert!(from_untrusted_fork(&event));
+    }
+
+    #[test]
+    fn has_label_finds_label_by_name() {
+        let event = fork_payload("NONE");
+
+        assert!(has_label(&event, "safe to test"));
+        assert!(!has_label(&event, "wip"));
+    }
+
+    #[test]
+    fn labeled_with_checks_added_label() {
+        let event = event("labeled");
+
+        assert!(labeled_with(&event, "safe to test"));
+        assert!(!labeled_with(&event, "wip"));
+    }
+
+    #[test]
+    fn dispatch_follows_event_action() {
+        let repo = RepoConfig {
+            run_labels: vec!["ci:run".to_owned()],
+            ..RepoConfig::default()
+        };
+
+        let ignored = Dispatch::Ignore("No status update needed.".to_owned());
+
+        let table = [
+            ("opened", None, Dispatch::Track),
+            ("synchronize", None, Dispatch::Track),
+            ("reopened", None, Dispatch::Track),
+            ("ready_for_review", None, Dispatch::Track),
+            ("labeled", Some("ci:run"), Dispatch::Track),
+            ("labeled", Some("wip"), ignored.clone()),
+            ("unlabeled", Some("ci:run"), ignored.clone()),
+            ("review_requested", None, ignored.clone()),
+            ("edited", None, ignored.clone()),
+            ("closed", None, Dispatch::Stop),
+        ];
+
+        for &(action, label, ref expected) in &table {
+            let mut event = event(action);
+            event.pull_request.draft = false;
+            event.label = label.map(|name| Label { name: name.to_owned() });
+
+            assert_eq!(dispatch(&event, &repo), *expected, "{} {:?}", action, label);
+        }
+    }
+
+    #[test]
+    fn dispatch_skips_drafts_until_ready() {
+        let repo = RepoConfig {
+            skip_drafts: true,
+            ..RepoConfig::default()
+        };
+        let waiting = Dispatch::Ignore(
+            "Waiting for the pull request to be ready for review.".to_owned()
         );
+
+        let table = [
+            ("opened", true, waiting.clone()),
+            ("synchronize", true, waiting.clone()),
+            ("closed", true, Dispatch::Stop),
+            ("opened", false, Dispatch::Track),
+            ("ready_for_review", false, Dispatch::Track),
+        ];
+
+        for &(action, draft, ref expected) in &table {
+            let mut event = event(action);
+            event.pull_request.draft = draft;
+
+            assert_eq!(dispatch(&event, &repo), *expected, "{} {}", action, draft);
+        }
+
+        // Drafts are tracked like other pull requests by default.
+        assert_eq!(dispatch(&event("opened"), &RepoConfig::default()), Dispatch::Track);
+    }
+
+    #[test]
+    fn dispatch_applies_fork_policy() {
+        let waiting = Dispatch::Ignore(
+            "Waiting for the \"safe to test\" label.".to_owned()
+        );
+        let skipped = Dispatch::Ignore(
+            "Not tracking pull requests from untrusted forks.".to_owned()
+        );
+
+        let table = [
+            (ForkPolicy::Track, "opened", true, Dispatch::Track),
+            (ForkPolicy::Skip, "opened", true, skipped.clone()),
+            (ForkPolicy::Label, "opened", false, waiting.clone()),
+            (ForkPolicy::Label, "synchronize", false, waiting.clone()),
+            (ForkPolicy::Label, "labeled", true, Dispatch::Track),
+            (ForkPolicy::Label, "synchronize", true, Dispatch::Track),
+        ];
+
+        for &(policy, action, has_label, ref expected) in &table {
+            let repo = RepoConfig {
+                untrusted_forks: policy,
+                fork_label: Some("safe to test".to_owned()),
+                ..RepoConfig::default()
+            };
+
+            let mut event = fork_payload("CONTRIBUTOR");
+            event.action = action.to_owned();
+            if !has_label {
+                event.pull_request.labels.clear();
+            }
+
+            assert_eq!(dispatch(&event, &repo), *expected, "{:?} {}", policy, action);
+        }
+    }
+
+    fn push_payload(git_ref: &str, after: &str) -> String {
+        format!(r#"{{
+            "ref": "{}",
+            "before": "6113728f27ae82c7b1a177c8d03f9e96e0adf246",
+            "after": "{}",
+            "deleted": false,
+            "repository": {{
+                "name": "Hello-World",
+                "full_name": "octocat/Hello-World",
+                "owner": {{ "name": "octocat", "login": "octocat" }}
+            }},
+            "pusher": {{ "name": "octocat" }},
+            "sender": {{ "login": "octocat" }},
+            "installation": {{ "id": 234 }}
+        }}"#, git_ref, after)
+    }
+
+    fn push_event(git_ref: &str, after: &str) -> PushEvent {
+        PushEvent::from_json(&push_payload(git_ref, after))
+            .expect("Failed to parse payload.")
+    }
+
+    #[test]
+    fn commit_ref_from_push_reads_branch_and_commit() {
+        let sha = "0d1a26e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c";
+
+        let commit_ref = CommitRef::from_push(
+            &push_event("refs/heads/feature/login", sha)
+        ).expect("Failed to create CommitRef from payload");
+
+        assert_eq!(commit_ref.owner, "octocat");
+        assert_eq!(commit_ref.repo, "Hello-World");
+        assert_eq!(commit_ref.sha, sha);
+        assert_eq!(commit_ref.branch, "feature/login");
+        assert_eq!(commit_ref.number, None);
+        assert_eq!(commit_ref.author, Some("octocat".to_owned()));
+        assert_eq!(commit_ref.installation_id, Some(234));
+
+        let tag = CommitRef::from_push(&push_event("refs/tags/v1.2.0", sha))
+            .expect("Failed to create CommitRef from payload");
+
+        assert_eq!(tag.branch, "v1.2.0");
     }
 
     #[test]
-    fn pull_request_opened_or_synchronized_returns_true_when_synchronized() {
+    fn push_event_requires_repository() {
         let payload = r#"{
-            "action": "synchronize"
+            "ref": "refs/heads/master",
+            "after": "0d1a26e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c"
         }"#;
 
-        let json = json::parse(payload)
-            .expect("Failed to parse payload.");
+        let error = PushEvent::from_json(payload)
+            .expect_err("Event should be invalid");
+
+        assert!(error.to_string().contains("missing field `repository`"));
+    }
+
+    #[test]
+    fn dispatch_push_ignores_deletions_and_tags() {
+        let sha = "0d1a26e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c";
+        let zeros = "0000000000000000000000000000000000000000";
+        let ignored = |reason: &str| Dispatch::Ignore(reason.to_owned());
+
+        let table = [
+            ("refs/heads/master", sha, false, Dispatch::Track),
+            ("refs/heads/feature/login", sha, false, Dispatch::Track),
+            ("refs/heads/feature/login", zeros, false, ignored("Branch deleted.")),
+            ("refs/tags/v1.2.0", sha, false, ignored("Not tracking tags.")),
+            ("refs/tags/v1.2.0", sha, true, Dispatch::Track),
+            ("refs/tags/v1.2.0", zeros, true, ignored("Branch deleted.")),
+            ("refs/notes/commits", sha, false, ignored("No status update needed.")),
+        ];
+
+        for &(git_ref, after, track_tags, ref expected) in &table {
+            let repo = RepoConfig {
+                track_tags,
+                ..RepoConfig::default()
+            };
+
+            assert_eq!(
+                dispatch_push(&push_event(git_ref, after), &repo),
+                *expected,
+                "{} {}",
+                git_ref,
+                after
+            );
+        }
 
+        let mut deleted = push_event("refs/heads/master", sha);
+        deleted.deleted = true;
         assert_eq!(
-            pull_request_opened_or_synchronized(json),
-            true
+            dispatch_push(&deleted, &RepoConfig::default()),
+            ignored("Branch deleted.")
         );
     }
 
+    const CHECK_RUN_PAYLOAD: &str = r#"{
+      "action": "rerequested",
+      "check_run": {
+        "id": 128620228,
+        "name": "Jenkins",
+        "head_sha": "ec26c3e57ca3a959ca5aad62de7213c562f8c821",
+        "status": "completed",
+        "conclusion": "failure",
+        "check_suite": {
+          "id": 118578147,
+          "head_branch": "changes",
+          "head_sha": "ec26c3e57ca3a959ca5aad62de7213c562f8c821"
+        },
+        "pull_requests": [
+          {
+            "url": "https://api.github.com/repos/Codertocat/Hello-World/pulls/2",
+            "id": 279147437,
+            "number": 2,
+            "head": {
+              "ref": "changes",
+              "sha": "ec26c3e57ca3a959ca5aad62de7213c562f8c821",
+              "repo": { "id": 186853002, "name": "Hello-World" }
+            },
+            "base": {
+              "ref": "master",
+              "sha": "f95f852bd8fca8fcc58a9a2d6c842781e32a215e",
+              "repo": { "id": 186853002, "name": "Hello-World" }
+            }
+          }
+        ]
+      },
+      "repository": {
+        "name": "Hello-World",
+        "full_name": "Codertocat/Hello-World",
+        "owner": { "login": "Codertocat" }
+      },
+      "installation": { "id": 2311213 }
+    }"#;
+
     #[test]
-    fn pull_request_opened_or_synchronized_returns_false_when_not_opened_or_synchronized() {
-        // "assigned", "unassigned", "review_requested",
-        // "review_request_removed", "labeled", "unlabeled", "opened",
-        // "edited", "closed", or "reopened"
-        let payload = r#"{
-            "action": "review_requested"
-        }"#;
+    fn commit_ref_from_check_run_reads_pull_request() {
+        let event = CheckRunEvent::from_json(CHECK_RUN_PAYLOAD)
+            .expect("Failed to parse payload.");
+
+        let commit_ref = CommitRef::from_check_run(&event)
+            .expect("Failed to create CommitRef from payload");
+
+        assert_eq!(commit_ref.owner, "Codertocat");
+        assert_eq!(commit_ref.repo, "Hello-World");
+        assert_eq!(commit_ref.sha, "ec26c3e57ca3a959ca5aad62de7213c562f8c821");
+        assert_eq!(commit_ref.branch, "changes");
+        assert_eq!(commit_ref.base_branch, Some("master".to_owned()));
+        assert_eq!(commit_ref.number, Some(2));
+        assert_eq!(commit_ref.installation_id, Some(2311213));
+    }
 
-        let json = json::parse(payload)
+    #[test]
+    fn dispatch_check_run_tracks_rerequested_runs() {
+        let mut event = CheckRunEvent::from_json(CHECK_RUN_PAYLOAD)
             .expect("Failed to parse payload.");
 
-        assert_eq!(
-            pull_request_opened_or_synchronized(json),
-            false
-        );
+        let table = [
+            ("rerequested", Dispatch::Track),
+            ("created", Dispatch::Ignore("No status update needed.".to_owned())),
+            ("completed", Dispatch::Ignore("No status update needed.".to_owned())),
+        ];
+
+        for &(action, ref expected) in &table {
+            event.action = action.to_owned();
+
+            assert_eq!(dispatch_check_run(&event), *expected, "{}", action);
+        }
     }
 }
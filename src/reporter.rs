@@ -0,0 +1,444 @@
+// Copyright © 2017 Teddy Wing
+//
+// This file is part of Kipper.
+//
+// Kipper is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Kipper is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kipper. If not, see <http://www.gnu.org/licenses/>.
+
+extern crate regex;
+extern crate reqwest;
+
+use std::error::Error;
+use std::str::FromStr;
+
+use self::regex::Regex;
+
+use config::Config;
+use github::{Annotation, CheckRun, CheckRunOutput, CommitStatus, GitHub};
+use jenkins::{self, TestFailure};
+use gitlab;
+use http;
+use pull_request::{CommitRef, Forge};
+
+const STATUS_CONTEXT: &str = "continuous-integration/jenkins";
+
+// GitHub rejects check run updates with more annotations than this.
+const MAX_ANNOTATIONS: usize = 50;
+
+// What we know about a commit's build at some point in time.
+pub struct Report {
+    pub status: CommitStatus,
+    pub build_url: Option<String>,
+    pub description: Option<String>,
+}
+
+// Publishes the progress of a build on GitHub.
+pub trait Reporter {
+    fn report(&self, commit_ref: &CommitRef, report: &Report) -> Result<(), Box<dyn Error>>;
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReporterKind {
+    #[default]
+    Status,
+    Checks,
+}
+
+impl FromStr for ReporterKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ReporterKind, String> {
+        match s {
+            "status" => Ok(ReporterKind::Status),
+            "checks" => Ok(ReporterKind::Checks),
+            _ => Err(
+                format!("unknown reporter '{}', expected 'status' or 'checks'", s)
+            ),
+        }
+    }
+}
+
+// Webhooks from a GitHub App installation are answered with that
+// installation's token. Otherwise, fall back to the configured token.
+fn github_token(
+    config: &Config,
+    commit_ref: &CommitRef,
+) -> Result<String, Box<dyn Error>> {
+    if let (Some(app), Some(installation_id)) =
+        (config.github.app.as_ref(), commit_ref.installation_id)
+    {
+        return app.installation_token(&config.github.api_url, installation_id)
+    }
+
+    config.github.token.as_ref()
+        .map(|token| token.expose().to_owned())
+        .ok_or_else(|| {
+            format!(
+                "no GitHub App installation for {}/{} and no `github.token` set",
+                commit_ref.owner,
+                commit_ref.repo
+            ).into()
+        })
+}
+
+// The reporter configured for the commit's repository. Commits on GitLab
+// always get commit statuses.
+pub fn for_commit(
+    config: &Config,
+    commit_ref: &CommitRef,
+) -> Result<Box<dyn Reporter>, Box<dyn Error>> {
+    if commit_ref.forge == Forge::GitLab {
+        let gitlab = config.gitlab.as_ref()
+            .ok_or("GitLab commit, but GitLab isn't configured")?;
+
+        return Ok(
+            Box::new(
+                GitLabReporter {
+                    url: gitlab.url.clone(),
+                    token: gitlab.token.expose().to_owned(),
+                }
+            )
+        )
+    }
+
+    let github = GitHub::new(
+        &config.github.api_url,
+        &github_token(config, commit_ref)?
+    );
+
+    match config.repo(&commit_ref.owner, &commit_ref.repo).reporter {
+        ReporterKind::Status => Ok(Box::new(StatusReporter { github })),
+        ReporterKind::Checks => Ok(
+            Box::new(
+                ChecksReporter {
+                    github,
+                    jenkins_url: config.jenkins.url.clone(),
+                    jenkins_client: jenkins::jenkins_request_client(
+                        &config.jenkins.user_id,
+                        &config.jenkins.token.expose().to_owned()
+                    )?,
+                }
+            )
+        ),
+    }
+}
+
+// Posts commit statuses.
+pub struct StatusReporter {
+    github: GitHub,
+}
+
+impl Reporter for StatusReporter {
+    fn report(&self, commit_ref: &CommitRef, report: &Report) -> Result<(), Box<dyn Error>> {
+        self.github.update_commit_status(
+            commit_ref,
+            &report.status,
+            report.build_url.as_ref().map(jenkins::jenkins_console_url_path),
+            report.description.clone(),
+            STATUS_CONTEXT.to_owned()
+        )
+    }
+}
+
+// Posts commit statuses on GitLab.
+pub struct GitLabReporter {
+    url: String,
+    token: String,
+}
+
+impl Reporter for GitLabReporter {
+    fn report(&self, commit_ref: &CommitRef, report: &Report) -> Result<(), Box<dyn Error>> {
+        gitlab::update_commit_status(
+            &self.url,
+            &self.token,
+            commit_ref,
+            &report.status,
+            report.build_url.as_ref().map(jenkins::jenkins_console_url_path),
+            report.description.clone(),
+            STATUS_CONTEXT.to_owned()
+        )
+    }
+}
+
+// Creates a check run and updates it as the build progresses. When the build
+// finishes, its duration and failed tests are added to the check run's
+// output, with annotations on the lines where tests failed.
+//
+// The Checks API is only available to GitHub Apps, so the GitHub token must
+// be an installation token.
+pub struct ChecksReporter {
+    github: GitHub,
+    jenkins_url: String,
+    jenkins_client: http::Client<'static>,
+}
+
+impl Reporter for ChecksReporter {
+    fn report(&self, commit_ref: &CommitRef, report: &Report) -> Result<(), Box<dyn Error>> {
+        let check_run = self.check_run(commit_ref, report)?;
+
+        // Look the check run up rather than remembering its ID, so that
+        // updates from notifications and after a restart go to the same one.
+        match self.github.find_check_run(commit_ref, STATUS_CONTEXT)? {
+            Some(id) => self.github.update_check_run(commit_ref, id, &check_run),
+            None => self.github.create_check_run(commit_ref, &check_run)
+                .map(|_| ()),
+        }
+    }
+}
+
+impl ChecksReporter {
+    fn check_run(
+        &self,
+        commit_ref: &CommitRef,
+        report: &Report,
+    ) -> Result<CheckRun, Box<dyn Error>> {
+        let (status, conclusion) = match report.status {
+            CommitStatus::Pending if report.build_url.is_none() => ("queued", None),
+            CommitStatus::Pending => ("in_progress", None),
+            CommitStatus::Success => ("completed", Some("success")),
+            CommitStatus::Failure => ("completed", Some("failure")),
+            CommitStatus::Error => ("completed", Some("cancelled")),
+        };
+
+        let title = report.description.clone()
+            .unwrap_or_else(|| title(&report.status).to_owned());
+
+        let output = match (conclusion, &report.build_url) {
+            (Some(_), Some(build_url)) => {
+                let job = jenkins::request_job(
+                    &self.jenkins_url,
+                    &self.jenkins_client,
+                    build_url
+                )?;
+                let failures = jenkins::get_test_failures(
+                    &self.jenkins_url,
+                    &self.jenkins_client,
+                    build_url
+                )?;
+
+                let location = location_regex();
+
+                Some(
+                    CheckRunOutput {
+                        title,
+                        summary: summary(job.duration, &failures),
+                        annotations: failures.iter()
+                            .filter_map(|failure| annotation(failure, &location))
+                            .take(MAX_ANNOTATIONS)
+                            .collect(),
+                    }
+                )
+            },
+            _ => Some(
+                CheckRunOutput {
+                    summary: title.clone(),
+                    title,
+                    annotations: Vec::new(),
+                }
+            ),
+        };
+
+        Ok(
+            CheckRun {
+                name: STATUS_CONTEXT.to_owned(),
+                head_sha: commit_ref.sha.clone(),
+                status: status.to_owned(),
+                conclusion: conclusion.map(str::to_owned),
+                details_url: report.build_url.as_ref()
+                    .map(jenkins::jenkins_console_url_path),
+                output,
+            }
+        )
+    }
+}
+
+fn title(status: &CommitStatus) -> &'static str {
+    match *status {
+        CommitStatus::Pending => "Build in progress",
+        CommitStatus::Success => "Build succeeded",
+        CommitStatus::Failure => "Build failed",
+        CommitStatus::Error => "Build errored",
+    }
+}
+
+fn summary(duration_ms: u64, failures: &[TestFailure]) -> String {
+    let seconds = duration_ms / 1000;
+    let mut summary = format!(
+        "Finished in {}m {}s.",
+        seconds / 60,
+        seconds % 60
+    );
+
+    if !failures.is_empty() {
+        summary.push_str(
+            &format!("\n\n**{} failing tests**\n", failures.len())
+        );
+
+        for failure in failures {
+            summary.push_str(&format!("\n- `{}`", failure.name));
+        }
+    }
+
+    summary
+}
+
+// Matches a `path:line` location in a stack trace.
+fn location_regex() -> Regex {
+    Regex::new(r"([\w./-]+\.\w+):(\d+)")
+        .expect("Invalid location regex")
+}
+
+// Annotate the first location in the failure's stack trace.
+fn annotation(failure: &TestFailure, location: &Regex) -> Option<Annotation> {
+    let captures = location.captures(&failure.stack_trace)?;
+    let path = captures[1].trim_start_matches("./").to_owned();
+    let line = captures[2].parse().ok()?;
+
+    Some(
+        Annotation {
+            path,
+            start_line: line,
+            end_line: line,
+            annotation_level: "failure".to_owned(),
+            title: failure.name.clone(),
+            message: if failure.details.is_empty() {
+                failure.stack_trace.clone()
+            } else {
+                failure.details.clone()
+            },
+        }
+    )
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate mockito;
+
+    use self::mockito::{mock, Matcher};
+
+    use super::*;
+
+    fn checks_reporter() -> ChecksReporter {
+        ChecksReporter {
+            github: GitHub::new(mockito::SERVER_URL, "token"),
+            jenkins_url: mockito::SERVER_URL.to_owned(),
+            jenkins_client: jenkins::jenkins_request_client(
+                &"username".to_owned(),
+                &"token".to_owned()
+            ).expect("Failed to build Jenkins request client"),
+        }
+    }
+
+    fn commit_ref(owner: &str) -> CommitRef {
+        CommitRef {
+            owner: owner.to_owned(),
+            number: Some(1347),
+            ..CommitRef::test_default()
+        }
+    }
+
+    #[test]
+    fn checks_reporter_creates_in_progress_check_run() {
+        let _lookup = mock(
+                "GET",
+                Matcher::Regex(
+                    "^/repos/checks-create/Hello-World/commits/6dcb09b5b57875f334f61aebed695e2e4193db5e/check-runs".to_owned()
+                )
+            )
+            .with_status(200)
+            .with_body(r#"{"total_count": 0, "check_runs": []}"#)
+            .create();
+        let create = mock("POST", "/repos/checks-create/Hello-World/check-runs")
+            .match_body(Matcher::Regex(r#""status":"in_progress""#.to_owned()))
+            .with_status(201)
+            .with_body(r#"{"id": 4}"#)
+            .create();
+
+        checks_reporter().report(
+            &commit_ref("checks-create"),
+            &Report {
+                status: CommitStatus::Pending,
+                build_url: Some("http://jenkins.example.com/job/Hello-World-branches/8/".to_owned()),
+                description: None,
+            }
+        ).expect("Failed to report");
+
+        create.assert();
+    }
+
+    #[test]
+    fn checks_reporter_completes_check_run_with_test_failures() {
+        let _lookup = mock(
+                "GET",
+                Matcher::Regex(
+                    "^/repos/checks-complete/Hello-World/commits/6dcb09b5b57875f334f61aebed695e2e4193db5e/check-runs".to_owned()
+                )
+            )
+            .with_status(200)
+            .with_body(r#"{"total_count": 1, "check_runs": [{"id": 21}]}"#)
+            .create();
+        let _build = mock("GET", "/job/checks-complete-branches/9/api/json")
+            .with_status(200)
+            .with_header("connection", "close")
+            .with_body(r#"{"displayName": "new-topic-6dcb0", "result": "FAILURE", "duration": 192000}"#)
+            .create();
+        let _tests = mock("GET", "/job/checks-complete-branches/9/testReport/api/json")
+            .with_status(200)
+            .with_header("connection", "close")
+            .with_body(r#"{"suites": [{"cases": [{
+                "className": "spec.widget_spec",
+                "name": "renders",
+                "status": "FAILED",
+                "errorDetails": "expected 42",
+                "errorStackTrace": "./spec/widget_spec.rb:12:in `block'"
+            }]}]}"#)
+            .create();
+        let update = mock("PATCH", "/repos/checks-complete/Hello-World/check-runs/21")
+            .match_body(Matcher::Regex(
+                r#""conclusion":"failure".*Finished in 3m 12s.*"path":"spec/widget_spec.rb","start_line":12"#.to_owned()
+            ))
+            .with_status(200)
+            .with_body(r#"{"id": 21}"#)
+            .create();
+
+        checks_reporter().report(
+            &commit_ref("checks-complete"),
+            &Report {
+                status: CommitStatus::Failure,
+                build_url: Some("http://jenkins.example.com/job/checks-complete-branches/9/".to_owned()),
+                description: None,
+            }
+        ).expect("Failed to report");
+
+        update.assert();
+    }
+
+    #[test]
+    fn annotation_needs_a_location() {
+        let failure = TestFailure {
+            name: "lint".to_owned(),
+            details: "".to_owned(),
+            stack_trace: "Process exited with status 1".to_owned(),
+        };
+
+        assert_eq!(annotation(&failure, &location_regex()), None);
+    }
+
+    #[test]
+    fn reporter_kind_from_str_rejects_unknown_reporters() {
+        assert_eq!("checks".parse(), Ok(ReporterKind::Checks));
+        assert!("slack".parse::<ReporterKind>().is_err());
+    }
+}
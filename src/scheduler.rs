@@ -0,0 +1,563 @@
+// Copyright © 2017 Teddy Wing
+//
+// This file is part of Kipper.
+//
+// Kipper is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Kipper is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kipper. If not, see <http://www.gnu.org/licenses/>.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use config::Config;
+use http::HostLimiter;
+use store::TrackerStore;
+use tracker::{self, Cancellation, Registry, Tracker};
+
+// Runs trackers on a fixed pool of worker threads. Between polls, trackers
+// wait in a queue ordered by when they're next due, rather than each holding
+// a sleeping thread.
+pub struct Scheduler {
+    config: Config,
+    store: Arc<TrackerStore>,
+    registry: Arc<Registry>,
+    jenkins_limiter: HostLimiter,
+    state: Mutex<State>,
+    condvar: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    // Trackers waiting for their next step. Trackers being run by a worker
+    // are taken out while they run.
+    waiting: HashMap<u64, Scheduled>,
+
+    // When each waiting tracker is due. An entry whose sequence number no
+    // longer matches its tracker's is left over from before a wake-up, and is
+    // skipped.
+    queue: BinaryHeap<Reverse<(Instant, u64, u64)>>,
+    next_sequence: u64,
+
+    // Waiting and running trackers.
+    active: usize,
+}
+
+struct Scheduled {
+    tracker: Tracker,
+    cancellation: Arc<Cancellation>,
+    sequence: u64,
+}
+
+impl State {
+    fn push(&mut self, mut scheduled: Scheduled, due: Instant) {
+        scheduled.sequence = self.next_sequence;
+        self.next_sequence += 1;
+
+        self.queue.push(Reverse((due, scheduled.sequence, scheduled.tracker.id)));
+        self.waiting.insert(scheduled.tracker.id, scheduled);
+    }
+}
+
+// Returned when `queue_limit` trackers are already running.
+#[derive(Debug)]
+pub struct QueueFull;
+
+impl fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "too many builds are being tracked")
+    }
+}
+
+impl Error for QueueFull {}
+
+impl Scheduler {
+    pub fn new(
+        config: Config,
+        store: Arc<TrackerStore>,
+        registry: Arc<Registry>,
+    ) -> Scheduler {
+        let jenkins_limiter = HostLimiter::new(
+            config.jenkins.max_concurrent_requests
+        );
+
+        Scheduler {
+            config,
+            store,
+            registry,
+            jenkins_limiter,
+            state: Mutex::new(State::default()),
+            condvar: Condvar::new(),
+        }
+    }
+
+    // Start the worker threads.
+    pub fn start(self: &Arc<Self>) {
+        for _ in 0..self.config.workers {
+            let scheduler = self.clone();
+
+            thread::spawn(move || scheduler.work());
+        }
+    }
+
+    // Run a tracker after waiting for `delay`, to give Jenkins time to create
+    // a build.
+    pub fn schedule(
+        self: &Arc<Self>,
+        tracker: Tracker,
+        delay: Duration,
+    ) -> Result<(), QueueFull> {
+        // The limit is checked before registering, which may cancel the
+        // tracker this one supersedes. It can be overshot by webhooks
+        // arriving at the same time.
+        if self.lock().active >= self.config.queue_limit {
+            return Err(QueueFull)
+        }
+
+        self.enqueue(tracker, delay);
+
+        Ok(())
+    }
+
+    // Pick up the trackers that were still running when Kipper last stopped.
+    // They're already in the journal, so they're resumed even if that goes
+    // over `queue_limit`; otherwise they'd stay there unfinished.
+    pub fn resume(self: &Arc<Self>) {
+        for tracker in self.store.active() {
+            info!(
+                "Resuming tracker for {}/{} {}",
+                tracker.commit_ref.owner,
+                tracker.commit_ref.repo,
+                tracker.commit_ref.sha
+            );
+
+            self.enqueue(tracker.clone(), Duration::from_secs(0));
+        }
+    }
+
+    fn enqueue(self: &Arc<Self>, tracker: Tracker, delay: Duration) {
+        let tracker_id = tracker.id;
+
+        // The commit is already being tracked, so this tracker isn't needed.
+        let cancellation = match self.registry.register(&tracker) {
+            Some(c) => c,
+            None => {
+                info!(
+                    "Already tracking {}/{} {}",
+                    tracker.commit_ref.owner,
+                    tracker.commit_ref.repo,
+                    tracker.commit_ref.sha
+                );
+
+                if let Err(e) = self.store.finish(tracker_id) {
+                    error!("{}", e.to_string());
+                }
+
+                return
+            },
+        };
+
+        {
+            let mut state = self.lock();
+
+            state.active += 1;
+            state.push(
+                Scheduled {
+                    tracker,
+                    cancellation: cancellation.clone(),
+                    sequence: 0,
+                },
+                Instant::now() + delay
+            );
+        }
+
+        self.condvar.notify_one();
+
+        let scheduler = Arc::downgrade(self);
+        cancellation.on_cancel(move || {
+            if let Some(scheduler) = scheduler.upgrade() {
+                scheduler.wake(tracker_id);
+            }
+        });
+    }
+
+    // Waiting and running trackers.
+    pub fn len(&self) -> usize {
+        self.lock().active
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Run a waiting tracker now, e.g. because it was cancelled.
+    fn wake(&self, tracker_id: u64) {
+        {
+            let mut state = self.lock();
+
+            match state.waiting.remove(&tracker_id) {
+                Some(scheduled) => state.push(scheduled, Instant::now()),
+                None => return,
+            }
+        }
+
+        self.condvar.notify_one();
+    }
+
+    fn work(&self) {
+        loop {
+            let mut scheduled = self.next_due();
+
+            // A bug in one tracker shouldn't take a worker down with it.
+            let delay = panic::catch_unwind(AssertUnwindSafe(|| {
+                tracker::advance(
+                    &mut scheduled.tracker,
+                    &self.config,
+                    &self.store,
+                    &self.registry,
+                    &scheduled.cancellation,
+                    &self.jenkins_limiter,
+                )
+            }));
+
+            self.reschedule(scheduled, delay);
+        }
+    }
+
+    // Put a tracker back in the queue after a step, or forget it once it's
+    // done or if it panicked.
+    fn reschedule(
+        &self,
+        scheduled: Scheduled,
+        delay: thread::Result<Option<Duration>>,
+    ) {
+        let delay = match delay {
+            Ok(delay) => delay,
+            Err(_) => {
+                error!(
+                    "Tracker for {}/{} {} panicked, no longer tracked",
+                    scheduled.tracker.commit_ref.owner,
+                    scheduled.tracker.commit_ref.repo,
+                    scheduled.tracker.commit_ref.sha
+                );
+
+                self.registry.deregister(&scheduled.tracker);
+
+                if let Err(e) = self.store.finish(scheduled.tracker.id) {
+                    error!("{}", e.to_string());
+                }
+
+                None
+            },
+        };
+
+        let mut state = self.lock();
+
+        match delay {
+            // A tracker cancelled while it was running is stopped right
+            // away.
+            Some(delay) => {
+                let due = if scheduled.cancellation.reason().is_some() {
+                    Instant::now()
+                } else {
+                    Instant::now() + delay
+                };
+
+                state.push(scheduled, due);
+                self.condvar.notify_one();
+            },
+            None => state.active -= 1,
+        }
+    }
+
+    // Wait for the next tracker that's due and take it out of the queue.
+    fn next_due(&self) -> Scheduled {
+        let mut state = self.lock();
+
+        loop {
+            let now = Instant::now();
+
+            let (due, sequence, tracker_id) = match state.queue.peek() {
+                Some(&Reverse(entry)) => entry,
+                None => {
+                    state = self.condvar.wait(state)
+                        .unwrap_or_else(|e| e.into_inner());
+
+                    continue
+                },
+            };
+
+            if due > now {
+                state = self.condvar.wait_timeout(state, due - now)
+                    .unwrap_or_else(|e| e.into_inner())
+                    .0;
+
+                continue
+            }
+
+            state.queue.pop();
+
+            let current = state.waiting.get(&tracker_id)
+                .is_some_and(|s| s.sequence == sequence);
+
+            if current {
+                if let Some(scheduled) = state.waiting.remove(&tracker_id) {
+                    return scheduled
+                }
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate mockito;
+
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::fs;
+
+    use self::mockito::{mock, Matcher};
+
+    use config::{GitHubConfig, JenkinsConfig, Secret};
+    use github::CommitStatus;
+    use http::unix_time;
+    use pull_request::CommitRef;
+
+    use super::*;
+
+    fn test_config() -> Config {
+        Config {
+            port: 8000,
+            state_file: None,
+            workers: 2,
+            queue_limit: 2,
+            jenkins: JenkinsConfig {
+                url: "http://jenkins.example.com".to_owned(),
+                user_id: "username".to_owned(),
+                token: Secret::new("token".to_owned()),
+                notification_token: None,
+                max_concurrent_requests: 1,
+            },
+            github: GitHubConfig {
+                api_url: "http://github.example.com".to_owned(),
+                token: Some(Secret::new("token".to_owned())),
+                app: None,
+                webhook_secret: None,
+                report_superseded: false,
+            },
+            gitlab: None,
+            repos: BTreeMap::new(),
+        }
+    }
+
+    fn commit_ref(number: u64) -> CommitRef {
+        CommitRef {
+            sha: format!("7c9d1e2f3a4b5c6d7e8f9a0b1c2d3e4f5a6b7c{:02}", number),
+            branch: "5120-slow-build".to_owned(),
+            number: Some(number),
+            ..CommitRef::test_default()
+        }
+    }
+
+    #[test]
+    fn schedule_refuses_trackers_over_queue_limit() {
+        let store = Arc::new(TrackerStore::in_memory());
+        let scheduler = Arc::new(
+            Scheduler::new(test_config(), store.clone(), Arc::new(Registry::new()))
+        );
+
+        for number in 1..3 {
+            let tracker = store.start(commit_ref(number))
+                .expect("Failed to start tracker");
+
+            scheduler.schedule(tracker, Duration::from_secs(30))
+                .expect("Failed to schedule tracker");
+        }
+
+        let tracker = store.start(commit_ref(3))
+            .expect("Failed to start tracker");
+
+        assert!(scheduler.schedule(tracker, Duration::from_secs(30)).is_err());
+        assert_eq!(scheduler.len(), 2);
+    }
+
+    #[test]
+    fn resume_ignores_queue_limit() {
+        let path = env::temp_dir().join("kipper-scheduler-test-resume-limit.journal");
+        let _ = fs::remove_file(&path);
+
+        {
+            let store = TrackerStore::open(&path)
+                .expect("Failed to open store");
+
+            for number in 1..4 {
+                store.start(commit_ref(number))
+                    .expect("Failed to start tracker");
+            }
+        }
+
+        let store = Arc::new(
+            TrackerStore::open(&path).expect("Failed to reopen store")
+        );
+        let scheduler = Arc::new(
+            Scheduler::new(test_config(), store, Arc::new(Registry::new()))
+        );
+
+        scheduler.resume();
+
+        assert_eq!(scheduler.len(), 3);
+    }
+
+    #[test]
+    fn panicked_tracker_is_forgotten() {
+        let store = Arc::new(TrackerStore::in_memory());
+        let registry = Arc::new(Registry::new());
+        let scheduler = Arc::new(
+            Scheduler::new(test_config(), store.clone(), registry.clone())
+        );
+
+        let tracker = store.start(commit_ref(5))
+            .expect("Failed to start tracker");
+
+        scheduler.schedule(tracker, Duration::from_secs(0))
+            .expect("Failed to schedule tracker");
+
+        let scheduled = scheduler.next_due();
+        let panic = panic::catch_unwind(|| -> Option<Duration> {
+            panic!("tracker bug")
+        });
+
+        scheduler.reschedule(scheduled, panic);
+
+        assert!(scheduler.is_empty());
+        assert!(!registry.cancel("octocat", "Hello-World", 5));
+    }
+
+    #[test]
+    fn cancelled_tracker_stops_without_waiting_for_its_turn() {
+        let store = Arc::new(TrackerStore::in_memory());
+        let registry = Arc::new(Registry::new());
+        let scheduler = Arc::new(
+            Scheduler::new(test_config(), store.clone(), registry.clone())
+        );
+        scheduler.start();
+
+        let tracker = store.start(commit_ref(7))
+            .expect("Failed to start tracker");
+
+        scheduler.schedule(tracker, Duration::from_secs(60 * 60))
+            .expect("Failed to schedule tracker");
+
+        assert!(registry.cancel("octocat", "Hello-World", 7));
+
+        let started = Instant::now();
+        while !scheduler.is_empty() && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn resumed_tracker_keeps_polling_its_build() {
+        let path = env::temp_dir().join("kipper-scheduler-test-resume.journal");
+        let _ = fs::remove_file(&path);
+
+        let mut config = test_config();
+        config.jenkins.url = mockito::SERVER_URL.to_owned();
+        config.github.api_url = format!("{}/resumed", mockito::SERVER_URL);
+
+        let build_url = format!(
+            "{}/job/resumed-Hello-World-branches/12/",
+            mockito::SERVER_URL
+        );
+
+        // The build was found and reported as pending before the restart.
+        {
+            let store = TrackerStore::open(&path)
+                .expect("Failed to open store");
+            let tracker = store.start(commit_ref(12))
+                .expect("Failed to start tracker");
+
+            store.build_found(
+                tracker.id,
+                &build_url,
+                unix_time() + 20 * 60
+            ).expect("Failed to record build");
+            store.status_posted(tracker.id, &CommitStatus::Pending)
+                .expect("Failed to record status");
+        }
+
+        let jenkins = mock("GET", "/job/resumed-Hello-World-branches/12/api/json")
+            .with_status(200)
+            .with_header("content-type", "application/json;charset=utf-8")
+            .with_body(r#"
+                {
+                  "displayName": "5120-slow-build-7c9d1",
+                  "result": "SUCCESS"
+                }
+            "#)
+            .expect(1)
+            .create();
+
+        let statuses = "/resumed/repos/octocat/Hello-World/statuses/7c9d1e2f3a4b5c6d7e8f9a0b1c2d3e4f5a6b7c12";
+
+        let pending = mock("POST", statuses)
+            .match_body(Matcher::Regex(r#""state":"pending""#.to_owned()))
+            .with_status(201)
+            .expect(0)
+            .create();
+
+        let success = mock("POST", statuses)
+            .match_body(Matcher::Regex(r#""state":"success""#.to_owned()))
+            .with_status(201)
+            .expect(1)
+            .create();
+
+        let store = Arc::new(
+            TrackerStore::open(&path).expect("Failed to reopen store")
+        );
+        let scheduler = Arc::new(
+            Scheduler::new(config, store.clone(), Arc::new(Registry::new()))
+        );
+        scheduler.start();
+        scheduler.resume();
+
+        let started = Instant::now();
+        while !scheduler.is_empty() && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert!(scheduler.is_empty());
+
+        jenkins.assert();
+        pending.assert();
+        success.assert();
+
+        let store = TrackerStore::open(&path)
+            .expect("Failed to reopen store");
+
+        assert!(store.active().is_empty());
+    }
+}
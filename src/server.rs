@@ -0,0 +1,1168 @@
+// Copyright © 2017 Teddy Wing
+//
+// This file is part of Kipper.
+//
+// Kipper is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Kipper is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kipper. If not, see <http://www.gnu.org/licenses/>.
+
+extern crate json;
+extern crate openssl;
+
+use std::io::Read;
+use std::sync::Arc;
+use std::time::Duration;
+
+use self::openssl::memcmp;
+use rouille;
+
+use config::Config;
+use github;
+use gitlab;
+use jenkins::Notification;
+use pull_request::{
+    dispatch,
+    dispatch_check_run,
+    dispatch_push,
+    CheckRunEvent,
+    CommitRef,
+    Dispatch,
+    PullRequestEvent,
+    PushEvent,
+};
+use scheduler::Scheduler;
+use store::TrackerStore;
+use tracker::{self, Registry};
+
+pub struct Context {
+    pub config: Config,
+    pub store: Arc<TrackerStore>,
+    pub registry: Arc<Registry>,
+    pub scheduler: Arc<Scheduler>,
+}
+
+#[allow(clippy::manual_strip)]
+pub fn handle(context: &Context, request: &rouille::Request) -> rouille::Response {
+    router!(request,
+        (POST) (/github/webhook) => {
+            github_webhook(context, request, None)
+        },
+
+        // Webhooks set up before there was a single endpoint.
+        (POST) (/github/pull_request_event) => {
+            github_webhook(context, request, Some("pull_request"))
+        },
+
+        (POST) (/github/push_event) => {
+            github_webhook(context, request, Some("push"))
+        },
+
+        (POST) (/gitlab/merge_request_event) => {
+            gitlab_merge_request_event(context, request)
+        },
+
+        (POST) (/jenkins/notification) => {
+            jenkins_notification(context, request)
+        },
+
+        _ => rouille::Response::text("404 Not Found")
+                .with_status_code(404)
+    )
+}
+
+// The body of a GitHub webhook request, once its signature is verified.
+fn github_webhook_body(
+    context: &Context,
+    request: &rouille::Request,
+) -> Result<String, rouille::Response> {
+    let mut data = match request.data() {
+        None => return Err(bad_request()),
+        Some(data) => data,
+    };
+
+    let mut body = String::new();
+    if data.read_to_string(&mut body).is_err() {
+        return Err(bad_request())
+    }
+
+    if let Some(ref secret) = context.config.github.webhook_secret {
+        let signature = match request.header("X-Hub-Signature-256") {
+            Some(s) => s,
+            None => {
+                warn!("Rejected webhook request without a signature");
+
+                return Err(unauthorized())
+            },
+        };
+
+        match github::webhook_signature_is_valid(
+            secret.expose(),
+            signature,
+            body.as_bytes()
+        ) {
+            Ok(true) => {},
+            Ok(false) => {
+                warn!("Rejected webhook request with an invalid signature");
+
+                return Err(unauthorized())
+            },
+            Err(e) => {
+                error!("{}", e.to_string());
+
+                return Err(internal_server_error())
+            },
+        }
+    }
+
+    Ok(body)
+}
+
+type GitHubHandler = fn(&Context, &str) -> rouille::Response;
+
+// Handlers for GitHub webhook events, by their `X-GitHub-Event` name.
+const GITHUB_HANDLERS: [(&str, GitHubHandler); 5] = [
+    ("ping", ping_event),
+    ("pull_request", pull_request_event),
+    ("push", push_event),
+    ("issue_comment", issue_comment_event),
+    ("check_run", check_run_event),
+];
+
+// Dispatch a GitHub webhook to the handler for its event. `default_event` is
+// for requests without an `X-GitHub-Event` header, on the older per-event
+// URLs.
+fn github_webhook(
+    context: &Context,
+    request: &rouille::Request,
+    default_event: Option<&str>,
+) -> rouille::Response {
+    let body = match github_webhook_body(context, request) {
+        Ok(body) => body,
+        Err(response) => return response,
+    };
+
+    let event = match request.header("X-GitHub-Event").or(default_event) {
+        Some(event) => event,
+        None => return rouille::Response::text("Missing X-GitHub-Event header.")
+            .with_status_code(400),
+    };
+
+    match GITHUB_HANDLERS.iter().find(|&&(name, _)| name == event) {
+        Some(&(_, handler)) => handler(context, &body),
+        None => {
+            info!("Ignored unsupported GitHub event '{}'", event);
+
+            rouille::Response::text("")
+                .with_status_code(204)
+        },
+    }
+}
+
+// Sent when a webhook is created.
+fn ping_event(_context: &Context, body: &str) -> rouille::Response {
+    let hook_id = json::parse(body).ok()
+        .and_then(|event| event["hook_id"].as_u64());
+
+    match hook_id {
+        Some(id) => rouille::Response::text(format!("Pong from hook {}.", id)),
+        None => rouille::Response::text("Pong."),
+    }
+}
+
+fn pull_request_event(context: &Context, body: &str) -> rouille::Response {
+    let event = match PullRequestEvent::from_json(body) {
+        Ok(e) => e,
+        Err(e) => {
+            warn!("{}", e);
+
+            return rouille::Response::text(e.to_string())
+                .with_status_code(400)
+        },
+    };
+
+    let commit_ref = match CommitRef::new(&event) {
+        Ok(cr) => cr,
+        Err(e) => {
+            warn!("{}", e);
+
+            return rouille::Response::text(e.to_string())
+                .with_status_code(400)
+        },
+    };
+
+    let repo_config = context.config.repo(&commit_ref.owner, &commit_ref.repo);
+
+    match dispatch(&event, &repo_config) {
+        Dispatch::Track => start_tracking(context, commit_ref),
+        Dispatch::Stop => {
+            let stopped = commit_ref.number.is_some_and(|number| {
+                context.registry.cancel(&commit_ref.owner, &commit_ref.repo, number)
+            });
+
+            if stopped {
+                rouille::Response::text("Tracking stopped.")
+                    .with_status_code(200)
+            } else {
+                rouille::Response::text(
+                    "No build is being tracked for this pull request."
+                ).with_status_code(200)
+            }
+        },
+        Dispatch::Ignore(reason) => rouille::Response::text(reason)
+            .with_status_code(200),
+    }
+}
+
+// Pushes to branches without a pull request, like the default branch after a
+// merge.
+fn push_event(context: &Context, body: &str) -> rouille::Response {
+    let event = match PushEvent::from_json(body) {
+        Ok(e) => e,
+        Err(e) => {
+            warn!("{}", e);
+
+            return rouille::Response::text(e.to_string())
+                .with_status_code(400)
+        },
+    };
+
+    let repo_config = context.config.repo(
+        &event.repository.owner.login,
+        &event.repository.name
+    );
+
+    if let Dispatch::Ignore(reason) = dispatch_push(&event, &repo_config) {
+        return rouille::Response::text(reason)
+            .with_status_code(200)
+    }
+
+    match CommitRef::from_push(&event) {
+        Ok(commit_ref) => start_tracking(context, commit_ref),
+        Err(e) => {
+            warn!("{}", e);
+
+            rouille::Response::text(e.to_string())
+                .with_status_code(400)
+        },
+    }
+}
+
+// Comments don't start builds, but are answered so that apps subscribed to
+// them don't see failed deliveries.
+fn issue_comment_event(_context: &Context, _body: &str) -> rouille::Response {
+    rouille::Response::text("No status update needed.")
+        .with_status_code(200)
+}
+
+// Check runs Kipper created that are re-run from GitHub.
+fn check_run_event(context: &Context, body: &str) -> rouille::Response {
+    let event = match CheckRunEvent::from_json(body) {
+        Ok(e) => e,
+        Err(e) => {
+            warn!("{}", e);
+
+            return rouille::Response::text(e.to_string())
+                .with_status_code(400)
+        },
+    };
+
+    if let Dispatch::Ignore(reason) = dispatch_check_run(&event) {
+        return rouille::Response::text(reason)
+            .with_status_code(200)
+    }
+
+    match CommitRef::from_check_run(&event) {
+        Ok(commit_ref) => start_tracking(context, commit_ref),
+        Err(e) => {
+            warn!("{}", e);
+
+            rouille::Response::text(e.to_string())
+                .with_status_code(400)
+        },
+    }
+}
+
+fn gitlab_merge_request_event(
+    context: &Context,
+    request: &rouille::Request,
+) -> rouille::Response {
+    let gitlab_config = match context.config.gitlab {
+        Some(ref g) => g,
+        None => return rouille::Response::text("404 Not Found")
+            .with_status_code(404),
+    };
+
+    if let Some(ref secret) = gitlab_config.webhook_secret {
+        match request.header("X-Gitlab-Token") {
+            Some(token) if gitlab::webhook_token_is_valid(secret.expose(), token) => {},
+            _ => {
+                warn!("Rejected GitLab webhook request with a missing or invalid token");
+
+                return unauthorized()
+            },
+        }
+    }
+
+    let mut data = match request.data() {
+        None => return bad_request(),
+        Some(data) => data,
+    };
+
+    let mut body = String::new();
+    try_or_400!(data.read_to_string(&mut body));
+
+    let json = match json::parse(body.as_ref()) {
+        Ok(j) => j,
+        Err(e) => {
+            warn!("Invalid GitLab webhook payload: {}", e);
+
+            return bad_request()
+        },
+    };
+
+    if json["object_kind"] != "merge_request" {
+        return rouille::Response::text("No status update needed.")
+            .with_status_code(200)
+    }
+
+    if gitlab::merge_request_closed(&json) {
+        if let Ok(CommitRef { owner, repo, number: Some(number), .. }) =
+            gitlab::commit_ref(&json) {
+            if context.registry.cancel(&owner, &repo, number) {
+                return rouille::Response::text("Tracking stopped.")
+                    .with_status_code(200)
+            }
+        }
+    }
+
+    if !gitlab::merge_request_needs_build(&json) {
+        return rouille::Response::text("No status update needed.")
+            .with_status_code(200)
+    }
+
+    match gitlab::commit_ref(&json) {
+        Ok(commit_ref) => start_tracking(context, commit_ref),
+        Err(e) => {
+            warn!("Invalid GitLab webhook payload: {}", e);
+
+            bad_request()
+        },
+    }
+}
+
+// Start following the Jenkins build of a commit in the background.
+fn start_tracking(
+    context: &Context,
+    commit_ref: CommitRef,
+) -> rouille::Response {
+    let tracker = match context.store.start(commit_ref) {
+        Ok(t) => t,
+        Err(e) => {
+            error!("{}", e.to_string());
+
+            return internal_server_error()
+        },
+    };
+
+    let tracker_id = tracker.id;
+
+    // The tracker posts a pending status straight away, then gives Jenkins
+    // time to start a build before looking for it.
+    if let Err(e) = context.scheduler.schedule(tracker, Duration::from_secs(0)) {
+        warn!("{}", e);
+
+        if let Err(e) = context.store.finish(tracker_id) {
+            error!("{}", e.to_string());
+        }
+
+        return service_unavailable()
+    }
+
+    rouille::Response::text("202 Accepted")
+        .with_status_code(202)
+}
+
+// Build events from the Jenkins Notification Plugin. They let us update
+// statuses as soon as a build starts or finishes rather than on the next
+// poll.
+fn jenkins_notification(
+    context: &Context,
+    request: &rouille::Request,
+) -> rouille::Response {
+    // Without a token anyone could post statuses, so the endpoint is only
+    // enabled when one is configured.
+    let token = match context.config.jenkins.notification_token {
+        Some(ref t) => t,
+        None => return rouille::Response::text("404 Not Found")
+            .with_status_code(404),
+    };
+
+    let given = request.get_param("token").unwrap_or_default();
+
+    if given.len() != token.expose().len()
+        || !memcmp::eq(given.as_bytes(), token.expose().as_bytes()) {
+        warn!("Rejected Jenkins notification with an invalid token");
+
+        return unauthorized()
+    }
+
+    let mut data = match request.data() {
+        None => return bad_request(),
+        Some(data) => data,
+    };
+
+    let mut body = String::new();
+    try_or_400!(data.read_to_string(&mut body));
+
+    let notification = match Notification::new(&body) {
+        Ok(n) => n,
+        Err(e) => {
+            warn!("Invalid Jenkins notification: {}", e);
+
+            return bad_request()
+        },
+    };
+
+    info!(
+        "Jenkins notification: {} {} {}",
+        notification.build_url,
+        notification.phase,
+        notification.sha
+    );
+
+    match tracker::notify(
+        &notification,
+        &context.config,
+        &context.store,
+        &context.registry
+    ) {
+        Ok(0) => rouille::Response::text("No build is being tracked for this commit.")
+            .with_status_code(200),
+        Ok(_) => rouille::Response::text("Status updated.")
+            .with_status_code(200),
+        Err(e) => {
+            error!("{}", e.to_string());
+
+            internal_server_error()
+        },
+    }
+}
+
+fn bad_request() -> rouille::Response {
+    rouille::Response::text("400 Bad Request")
+        .with_status_code(400)
+}
+
+fn unauthorized() -> rouille::Response {
+    rouille::Response::text("401 Unauthorized")
+        .with_status_code(401)
+}
+
+fn internal_server_error() -> rouille::Response {
+    rouille::Response::text("500 Internal Server Error")
+        .with_status_code(500)
+}
+
+fn service_unavailable() -> rouille::Response {
+    rouille::Response::text("503 Service Unavailable")
+        .with_status_code(503)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use config::{GitHubConfig, GitLabConfig, JenkinsConfig, RepoConfig, Secret};
+    use pull_request::ForkPolicy;
+
+    use super::*;
+
+    const SECRET: &str = "It's a Secret to Everybody";
+    const NOTIFICATION_TOKEN: &str = "jenkins-notification-token";
+    const GITLAB_SECRET: &str = "gitlab-webhook-secret";
+    const PAYLOAD: &str = r#"{"action": "closed", "number": 7, "pull_request": {"title": "Close me", "html_url": "https://github.com/octocat/Hello-World/pull/7", "user": {"login": "octocat"}, "head": {"ref": "close-me", "sha": "6dcb09b5b57875f334f61aebed695e2e4193db5e", "repo": null}, "base": {"ref": "master", "sha": "553c2077f0edc3d5dc5d17262f6aa498e69d6f8e", "repo": {"name": "Hello-World", "full_name": "octocat/Hello-World", "owner": {"login": "octocat"}}}}}"#;
+
+    fn test_context() -> Context {
+        let config = Config {
+            port: 8000,
+            state_file: None,
+            workers: 1,
+            queue_limit: 10,
+            jenkins: JenkinsConfig {
+                url: "http://jenkins.example.com".to_owned(),
+                user_id: "username".to_owned(),
+                token: Secret::new("token".to_owned()),
+                notification_token: Some(Secret::new(NOTIFICATION_TOKEN.to_owned())),
+                max_concurrent_requests: 1,
+            },
+            github: GitHubConfig {
+                api_url: "http://github.example.com".to_owned(),
+                token: Some(Secret::new("token".to_owned())),
+                app: None,
+                webhook_secret: Some(Secret::new(SECRET.to_owned())),
+                report_superseded: false,
+            },
+            gitlab: Some(GitLabConfig {
+                url: "http://gitlab.example.com".to_owned(),
+                token: Secret::new("token".to_owned()),
+                webhook_secret: Some(Secret::new(GITLAB_SECRET.to_owned())),
+            }),
+            repos: BTreeMap::new(),
+        };
+        let store = Arc::new(TrackerStore::in_memory());
+        let registry = Arc::new(Registry::new());
+
+        // Not started, so trackers stay in the queue.
+        let scheduler = Arc::new(
+            Scheduler::new(config.clone(), store.clone(), registry.clone())
+        );
+
+        Context {
+            config,
+            store,
+            registry,
+            scheduler,
+        }
+    }
+
+    fn pull_request_event_request(
+        signature: Option<&str>
+    ) -> rouille::Request {
+        let mut headers = vec![
+            ("Content-Type".to_owned(), "application/json".to_owned()),
+        ];
+
+        if let Some(s) = signature {
+            headers.push(("X-Hub-Signature-256".to_owned(), s.to_owned()));
+        }
+
+        rouille::Request::fake_http(
+            "POST",
+            "/github/pull_request_event",
+            headers,
+            PAYLOAD.as_bytes().to_vec()
+        )
+    }
+
+    #[test]
+    fn pull_request_event_accepts_valid_signature() {
+        let request = pull_request_event_request(
+            Some("sha256=c485700db2cf672bc2cda1a1cf78eecea988ca5a9e2489a3793b009016714c9c")
+        );
+
+        let response = handle(&test_context(), &request);
+
+        assert_eq!(response.status_code, 200);
+    }
+
+    #[test]
+    fn pull_request_event_rejects_missing_signature() {
+        let request = pull_request_event_request(None);
+
+        let response = handle(&test_context(), &request);
+
+        assert_eq!(response.status_code, 401);
+    }
+
+    #[test]
+    fn pull_request_event_rejects_forged_signature() {
+        let request = pull_request_event_request(
+            Some("sha256=0000000000000000000000000000000000000000000000000000000000000000")
+        );
+
+        let response = handle(&test_context(), &request);
+
+        assert_eq!(response.status_code, 401);
+    }
+
+    #[test]
+    fn pull_request_event_skips_verification_without_secret() {
+        let mut context = test_context();
+        context.config.github.webhook_secret = None;
+        let request = pull_request_event_request(None);
+
+        let response = handle(&context, &request);
+
+        assert_eq!(response.status_code, 200);
+    }
+
+    #[test]
+    fn pull_request_event_rejects_incomplete_payload() {
+        let mut context = test_context();
+        context.config.github.webhook_secret = None;
+
+        let payload = pull_request_payload("opened", 3, "octocat", &[])
+            .replace(r#""sha": "1f2e3d4c5b6a79881f2e3d4c5b6a79881f2e3d4c","#, "");
+
+        let response = handle(&context, &pull_request_request(payload));
+
+        assert_eq!(response.status_code, 400);
+        assert!(context.scheduler.is_empty());
+    }
+
+    #[test]
+    fn pull_request_event_closed_cancels_tracker() {
+        let mut context = test_context();
+        context.config.github.webhook_secret = None;
+
+        let cancellation = context.registry.register(&tracker::Tracker {
+            id: 1,
+            commit_ref: CommitRef {
+                branch: "2240-close-me".to_owned(),
+                number: Some(5),
+                ..CommitRef::test_default()
+            },
+            started_at: 0,
+            queue_url: None,
+            queued_reason: None,
+            build_url: None,
+            last_status: None,
+            deadline: None,
+        }).expect("Failed to register tracker");
+
+        let request = pull_request_request(
+            pull_request_payload("closed", 5, "octocat", &[])
+        );
+
+        let response = handle(&context, &request);
+
+        assert_eq!(response.status_code, 200);
+        assert_eq!(cancellation.reason(), Some(tracker::CancelReason::Closed));
+    }
+
+    // A pull request opened on octocat/Hello-World from `head_owner`'s
+    // repository, a fork unless it's octocat.
+    fn pull_request_payload(
+        action: &str,
+        number: u64,
+        head_owner: &str,
+        labels: &[&str],
+    ) -> String {
+        let labels = labels.iter()
+            .map(|l| format!(r#"{{ "name": "{}" }}"#, l))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        format!(r#"{{
+            "action": "{action}",
+            "number": {number},
+            "label": {{ "name": "safe to test" }},
+            "pull_request": {{
+                "title": "Busy morning",
+                "html_url": "https://github.com/octocat/Hello-World/pull/{number}",
+                "user": {{ "login": "{head_owner}" }},
+                "author_association": "FIRST_TIME_CONTRIBUTOR",
+                "labels": [{labels}],
+                "base": {{
+                    "ref": "master",
+                    "sha": "553c2077f0edc3d5dc5d17262f6aa498e69d6f8e",
+                    "repo": {{
+                        "name": "Hello-World",
+                        "full_name": "octocat/Hello-World",
+                        "owner": {{ "login": "octocat" }}
+                    }}
+                }},
+                "head": {{
+                    "ref": "4410-busy-morning",
+                    "sha": "1f2e3d4c5b6a79881f2e3d4c5b6a79881f2e3d4c",
+                    "repo": {{
+                        "name": "Hello-World",
+                        "full_name": "{head_owner}/Hello-World",
+                        "owner": {{ "login": "{head_owner}" }}
+                    }}
+                }}
+            }}
+        }}"#,
+            action = action,
+            number = number,
+            head_owner = head_owner,
+            labels = labels
+        )
+    }
+
+    fn pull_request_request(payload: String) -> rouille::Request {
+        rouille::Request::fake_http(
+            "POST",
+            "/github/pull_request_event",
+            vec![],
+            payload.into_bytes()
+        )
+    }
+
+    fn synchronize_event_request(number: u64) -> rouille::Request {
+        pull_request_request(
+            pull_request_payload("synchronize", number, "octocat", &[])
+        )
+    }
+
+    #[test]
+    fn pull_request_event_is_refused_when_queue_is_full() {
+        let mut context = test_context();
+        context.config.github.webhook_secret = None;
+
+        let mut config = context.config.clone();
+        config.queue_limit = 1;
+        context.scheduler = Arc::new(
+            Scheduler::new(config, context.store.clone(), context.registry.clone())
+        );
+
+        let response = handle(&context, &synchronize_event_request(1));
+        assert_eq!(response.status_code, 202);
+
+        let response = handle(&context, &synchronize_event_request(2));
+        assert_eq!(response.status_code, 503);
+
+        assert_eq!(context.scheduler.len(), 1);
+    }
+
+    #[test]
+    fn pull_request_event_tracks_reopened_pull_request() {
+        let mut context = test_context();
+        context.config.github.webhook_secret = None;
+
+        let response = handle(
+            &context,
+            &pull_request_request(
+                pull_request_payload("reopened", 8, "octocat", &[])
+            )
+        );
+
+        assert_eq!(response.status_code, 202);
+        assert_eq!(context.scheduler.len(), 1);
+    }
+
+    fn push_event_request(git_ref: &str, after: &str) -> rouille::Request {
+        rouille::Request::fake_http(
+            "POST",
+            "/github/push_event",
+            vec![],
+            format!(r#"{{
+                "ref": "{}",
+                "after": "{}",
+                "repository": {{
+                    "name": "Hello-World",
+                    "full_name": "octocat/Hello-World",
+                    "owner": {{ "name": "octocat", "login": "octocat" }}
+                }}
+            }}"#, git_ref, after).into_bytes()
+        )
+    }
+
+    #[test]
+    fn push_event_tracks_pushed_branch() {
+        let mut context = test_context();
+        context.config.github.webhook_secret = None;
+
+        let response = handle(
+            &context,
+            &push_event_request(
+                "refs/heads/master",
+                "0d1a26e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c"
+            )
+        );
+
+        assert_eq!(response.status_code, 202);
+        assert_eq!(context.scheduler.len(), 1);
+    }
+
+    #[test]
+    fn push_event_skips_head_of_tracked_pull_request() {
+        let mut context = test_context();
+        context.config.github.webhook_secret = None;
+
+        let response = handle(
+            &context,
+            &pull_request_request(pull_request_payload("opened", 15, "octocat", &[]))
+        );
+        assert_eq!(response.status_code, 202);
+
+        handle(
+            &context,
+            &push_event_request(
+                "refs/heads/4410-busy-morning",
+                "1f2e3d4c5b6a79881f2e3d4c5b6a79881f2e3d4c"
+            )
+        );
+
+        assert_eq!(context.scheduler.len(), 1);
+    }
+
+    #[test]
+    fn push_event_ignores_deleted_branch() {
+        let mut context = test_context();
+        context.config.github.webhook_secret = None;
+
+        let response = handle(
+            &context,
+            &push_event_request(
+                "refs/heads/old-feature",
+                "0000000000000000000000000000000000000000"
+            )
+        );
+
+        assert_eq!(response.status_code, 200);
+        assert!(context.scheduler.is_empty());
+    }
+
+    #[test]
+    fn push_event_rejects_missing_signature() {
+        let response = handle(
+            &test_context(),
+            &push_event_request(
+                "refs/heads/master",
+                "0d1a26e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c"
+            )
+        );
+
+        assert_eq!(response.status_code, 401);
+    }
+
+    fn webhook_request(
+        path: &str,
+        event: Option<&str>,
+        payload: String,
+    ) -> rouille::Request {
+        let headers = event.into_iter()
+            .map(|e| ("X-GitHub-Event".to_owned(), e.to_owned()))
+            .collect();
+
+        rouille::Request::fake_http("POST", path, headers, payload.into_bytes())
+    }
+
+    fn response_body(response: rouille::Response) -> String {
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut body = String::new();
+        reader.read_to_string(&mut body)
+            .expect("Failed to read response");
+
+        body
+    }
+
+    #[test]
+    fn webhook_dispatches_on_event_header() {
+        let mut context = test_context();
+        context.config.github.webhook_secret = None;
+
+        let ping = r#"{ "zen": "Keep it logically awesome.", "hook_id": 30 }"#;
+        let table = [
+            ("ping", ping.to_owned(), 200),
+            ("pull_request", pull_request_payload("opened", 4, "octocat", &[]), 202),
+            ("issue_comment", r#"{ "action": "created" }"#.to_owned(), 200),
+            ("star", r#"{ "action": "created" }"#.to_owned(), 204),
+        ];
+
+        for &(event, ref payload, status) in &table {
+            let response = handle(
+                &context,
+                &webhook_request("/github/webhook", Some(event), payload.clone())
+            );
+
+            assert_eq!(response.status_code, status, "{}", event);
+        }
+
+        assert_eq!(context.scheduler.len(), 1);
+    }
+
+    #[test]
+    fn webhook_answers_ping_with_hook_id() {
+        let mut context = test_context();
+        context.config.github.webhook_secret = None;
+
+        // Older webhooks are sent their ping on the per-event URL.
+        let response = handle(
+            &context,
+            &webhook_request(
+                "/github/pull_request_event",
+                Some("ping"),
+                r#"{ "zen": "Design for failure.", "hook_id": 42 }"#.to_owned()
+            )
+        );
+
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response_body(response), "Pong from hook 42.");
+    }
+
+    #[test]
+    fn webhook_requires_event_header() {
+        let mut context = test_context();
+        context.config.github.webhook_secret = None;
+
+        let response = handle(
+            &context,
+            &webhook_request(
+                "/github/webhook",
+                None,
+                pull_request_payload("opened", 4, "octocat", &[])
+            )
+        );
+
+        assert_eq!(response.status_code, 400);
+    }
+
+    #[test]
+    fn webhook_verifies_signature_before_dispatch() {
+        let response = handle(
+            &test_context(),
+            &webhook_request("/github/webhook", Some("ping"), "{}".to_owned())
+        );
+
+        assert_eq!(response.status_code, 401);
+    }
+
+    #[test]
+    fn webhook_tracks_rerequested_check_run() {
+        let mut context = test_context();
+        context.config.github.webhook_secret = None;
+
+        let payload = r#"{
+            "action": "rerequested",
+            "check_run": {
+                "head_sha": "ec26c3e57ca3a959ca5aad62de7213c562f8c821",
+                "check_suite": { "head_branch": "changes" },
+                "pull_requests": []
+            },
+            "repository": {
+                "name": "Hello-World",
+                "full_name": "octocat/Hello-World",
+                "owner": { "login": "octocat" }
+            }
+        }"#;
+
+        let response = handle(
+            &context,
+            &webhook_request("/github/webhook", Some("check_run"), payload.to_owned())
+        );
+
+        assert_eq!(response.status_code, 202);
+        assert_eq!(context.scheduler.len(), 1);
+    }
+
+    fn fork_event_request(action: &str, labels: &[&str]) -> rouille::Request {
+        pull_request_request(
+            pull_request_payload(action, 12, "contributor", labels)
+        )
+    }
+
+    fn fork_policy_context(policy: ForkPolicy) -> Context {
+        let mut context = test_context();
+        context.config.github.webhook_secret = None;
+        context.config.repos.insert(
+            "octocat/Hello-World".to_owned(),
+            RepoConfig {
+                untrusted_forks: policy,
+                fork_label: Some("safe to test".to_owned()),
+                ..RepoConfig::default()
+            },
+        );
+
+        context
+    }
+
+    #[test]
+    fn pull_request_event_tracks_fork_by_default() {
+        let context = fork_policy_context(ForkPolicy::Track);
+
+        let response = handle(&context, &fork_event_request("opened", &[]));
+        assert_eq!(response.status_code, 202);
+        assert_eq!(context.scheduler.len(), 1);
+    }
+
+    #[test]
+    fn pull_request_event_skips_untrusted_fork() {
+        let context = fork_policy_context(ForkPolicy::Skip);
+
+        let response = handle(&context, &fork_event_request("opened", &[]));
+
+        assert_eq!(response.status_code, 200);
+        assert!(context.scheduler.is_empty());
+    }
+
+    #[test]
+    fn pull_request_event_waits_for_fork_label() {
+        let context = fork_policy_context(ForkPolicy::Label);
+
+        let response = handle(&context, &fork_event_request("opened", &[]));
+        assert_eq!(response.status_code, 200);
+        assert!(context.scheduler.is_empty());
+
+        let response = handle(
+            &context,
+            &fork_event_request("labeled", &["safe to test"])
+        );
+        assert_eq!(response.status_code, 202);
+
+        // The push is of the head that's already being tracked.
+        let response = handle(
+            &context,
+            &fork_event_request("synchronize", &["safe to test"])
+        );
+        assert_eq!(response.status_code, 202);
+        assert_eq!(context.scheduler.len(), 1);
+    }
+
+    #[test]
+    fn pull_request_event_keeps_tracker_when_relabelled() {
+        let mut context = test_context();
+        context.config.github.webhook_secret = None;
+        context.config.repos.insert(
+            "octocat/Hello-World".to_owned(),
+            RepoConfig {
+                run_labels: vec!["safe to test".to_owned()],
+                ..RepoConfig::default()
+            },
+        );
+
+        let response = handle(
+            &context,
+            &pull_request_request(pull_request_payload("opened", 14, "octocat", &[]))
+        );
+        assert_eq!(response.status_code, 202);
+
+        let tracked = context.registry
+            .find_commit("1f2e3d4c5b6a79881f2e3d4c5b6a79881f2e3d4c");
+        assert_eq!(tracked.len(), 1);
+
+        let response = handle(
+            &context,
+            &pull_request_request(
+                pull_request_payload("labeled", 14, "octocat", &["safe to test"])
+            )
+        );
+        assert_eq!(response.status_code, 202);
+
+        assert_eq!(context.scheduler.len(), 1);
+        assert_eq!(tracked[0].cancellation.reason(), None);
+    }
+
+    fn jenkins_notification_request(token: &str) -> rouille::Request {
+        rouille::Request::fake_http(
+            "POST",
+            format!("/jenkins/notification?token={}", token),
+            vec![("Content-Type".to_owned(), "application/json".to_owned())],
+            br#"{
+                "build": {
+                    "full_url": "http://jenkins.example.com/job/Hello-World-branches/3/",
+                    "phase": "STARTED",
+                    "scm": { "commit": "d0e1f2a3b4c5d6e7f8a9b0c1d2e3f4a5b6c7d8e9" }
+                }
+            }"#.to_vec()
+        )
+    }
+
+    #[test]
+    fn jenkins_notification_accepts_valid_token() {
+        let request = jenkins_notification_request(NOTIFICATION_TOKEN);
+
+        let response = handle(&test_context(), &request);
+
+        assert_eq!(response.status_code, 200);
+    }
+
+    #[test]
+    fn jenkins_notification_rejects_invalid_token() {
+        let request = jenkins_notification_request("guessed-token");
+
+        let response = handle(&test_context(), &request);
+
+        assert_eq!(response.status_code, 401);
+    }
+
+    #[test]
+    fn jenkins_notification_is_disabled_without_token() {
+        let mut context = test_context();
+        context.config.jenkins.notification_token = None;
+
+        let response = handle(&context, &jenkins_notification_request(""));
+
+        assert_eq!(response.status_code, 404);
+    }
+
+    #[test]
+    fn jenkins_notification_rejects_malformed_payload() {
+        let request = rouille::Request::fake_http(
+            "POST",
+            format!("/jenkins/notification?token={}", NOTIFICATION_TOKEN),
+            vec![],
+            br#"{"build": {"phase": "STARTED"}}"#.to_vec()
+        );
+
+        let response = handle(&test_context(), &request);
+
+        assert_eq!(response.status_code, 400);
+    }
+
+    fn gitlab_merge_request_event_request(token: Option<&str>) -> rouille::Request {
+        let mut headers = vec![
+            ("Content-Type".to_owned(), "application/json".to_owned()),
+            ("X-Gitlab-Event".to_owned(), "Merge Request Hook".to_owned()),
+        ];
+
+        if let Some(t) = token {
+            headers.push(("X-Gitlab-Token".to_owned(), t.to_owned()));
+        }
+
+        rouille::Request::fake_http(
+            "POST",
+            "/gitlab/merge_request_event",
+            headers,
+            br#"{
+                "object_kind": "merge_request",
+                "object_attributes": {
+                    "iid": 1,
+                    "action": "update",
+                    "source_branch": "ms-viewport",
+                    "source": { "path_with_namespace": "awesome_space/awesome_project" },
+                    "last_commit": { "id": "da1560886d4f094c3e6c9ef40349f7d38b5d27d7" }
+                }
+            }"#.to_vec()
+        )
+    }
+
+    #[test]
+    fn gitlab_merge_request_event_accepts_valid_token() {
+        let request = gitlab_merge_request_event_request(Some(GITLAB_SECRET));
+
+        let response = handle(&test_context(), &request);
+
+        assert_eq!(response.status_code, 200);
+    }
+
+    #[test]
+    fn gitlab_merge_request_event_rejects_missing_token() {
+        let request = gitlab_merge_request_event_request(None);
+
+        let response = handle(&test_context(), &request);
+
+        assert_eq!(response.status_code, 401);
+    }
+
+    #[test]
+    fn gitlab_merge_request_event_rejects_wrong_token() {
+        let request = gitlab_merge_request_event_request(Some("not-the-secret"));
+
+        let response = handle(&test_context(), &request);
+
+        assert_eq!(response.status_code, 401);
+    }
+
+    #[test]
+    fn gitlab_merge_request_event_is_not_found_without_gitlab() {
+        let mut context = test_context();
+        context.config.gitlab = None;
+        let request = gitlab_merge_request_event_request(Some(GITLAB_SECRET));
+
+        let response = handle(&context, &request);
+
+        assert_eq!(response.status_code, 404);
+    }
+}
@@ -0,0 +1,506 @@
+// Copyright © 2017 Teddy Wing
+//
+// This file is part of Kipper.
+//
+// Kipper is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Kipper is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kipper. If not, see <http://www.gnu.org/licenses/>.
+
+extern crate json;
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use github::CommitStatus;
+use http::unix_time;
+use pull_request::{CommitRef, Forge};
+use tracker::Tracker;
+
+// Keeps a record of the builds being tracked so they can be picked up again
+// after a restart.
+//
+// The record is an append-only journal with one JSON event per line:
+//
+//   {"event":"start","id":1,"started_at":1511350800,"owner":"…","repo":"…","head_repo":"…","sha":"…","branch":"…","base_branch":"…","number":12,"author":"…","title":"…","labels":["…"],"forge":"github","installation_id":234}
+//   {"event":"queue","id":1,"queue_url":"…"}
+//   {"event":"build","id":1,"build_url":"…","deadline":1511352000}
+//   {"event":"status","id":1,"state":"pending"}
+//   {"event":"finish","id":1}
+//
+// When the journal is opened, finished trackers are dropped and it is
+// rewritten with only the active ones.
+pub struct TrackerStore {
+    journal: Mutex<Journal>,
+    active: Vec<Tracker>,
+}
+
+struct Journal {
+    file: Option<File>,
+    next_id: u64,
+}
+
+impl TrackerStore {
+    pub fn open(path: &Path) -> Result<TrackerStore, Box<dyn Error>> {
+        let trackers = if path.exists() {
+            replay(path)?
+        } else {
+            BTreeMap::new()
+        };
+
+        let next_id = trackers.keys().last().map_or(1, |id| id + 1);
+
+        compact(path, trackers.values())?;
+
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        Ok(
+            TrackerStore {
+                journal: Mutex::new(Journal {
+                    file: Some(file),
+                    next_id,
+                }),
+                active: trackers.into_values().collect(),
+            }
+        )
+    }
+
+    // A store that doesn't persist anything, for when no state file is
+    // configured.
+    pub fn in_memory() -> TrackerStore {
+        TrackerStore {
+            journal: Mutex::new(Journal {
+                file: None,
+                next_id: 1,
+            }),
+            active: Vec::new(),
+        }
+    }
+
+    // Trackers that hadn't finished when the store was opened.
+    pub fn active(&self) -> &[Tracker] {
+        &self.active
+    }
+
+    pub fn start(&self, commit_ref: CommitRef) -> Result<Tracker, Box<dyn Error>> {
+        let mut journal = self.journal.lock()
+            .map_err(|_| "tracker journal lock poisoned")?;
+
+        let tracker = Tracker {
+            id: journal.next_id,
+            commit_ref,
+            started_at: unix_time(),
+            queue_url: None,
+            queued_reason: None,
+            build_url: None,
+            last_status: None,
+            deadline: None,
+        };
+        journal.next_id += 1;
+
+        journal.append(&start_event(&tracker))?;
+
+        Ok(tracker)
+    }
+
+    pub fn build_queued(
+        &self,
+        id: u64,
+        queue_url: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        self.append(&queue_event(id, queue_url))
+    }
+
+    pub fn build_found(
+        &self,
+        id: u64,
+        build_url: &str,
+        deadline: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        self.append(&build_event(id, build_url, deadline))
+    }
+
+    pub fn status_posted(
+        &self,
+        id: u64,
+        status: &CommitStatus,
+    ) -> Result<(), Box<dyn Error>> {
+        self.append(&status_event(id, status))
+    }
+
+    pub fn finish(&self, id: u64) -> Result<(), Box<dyn Error>> {
+        self.append(&event("finish", id))
+    }
+
+    fn append(&self, event: &json::JsonValue) -> Result<(), Box<dyn Error>> {
+        let mut journal = self.journal.lock()
+            .map_err(|_| "tracker journal lock poisoned")?;
+
+        journal.append(event)
+    }
+}
+
+impl Journal {
+    fn append(&mut self, event: &json::JsonValue) -> Result<(), Box<dyn Error>> {
+        if let Some(ref mut file) = self.file {
+            writeln!(file, "{}", event.dump())?;
+            file.sync_data()?;
+        }
+
+        Ok(())
+    }
+}
+
+fn replay(path: &Path) -> Result<BTreeMap<u64, Tracker>, Box<dyn Error>> {
+    let mut trackers = BTreeMap::new();
+
+    let file = File::open(path)?;
+
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        // The last line may be incomplete if we were killed in the middle
+        // of writing it.
+        let mut event = match json::parse(&line) {
+            Ok(e) => e,
+            Err(e) => {
+                warn!("Skipping line {} of {}: {}", i + 1, path.display(), e);
+
+                continue;
+            },
+        };
+
+        let id = match event["id"].as_u64() {
+            Some(id) => id,
+            None => {
+                warn!("Skipping line {} of {}: missing id", i + 1, path.display());
+
+                continue;
+            },
+        };
+
+        match event["event"].as_str() {
+            Some("start") => {
+                trackers.insert(id, Tracker {
+                    id,
+                    commit_ref: CommitRef {
+                        owner: event["owner"].take_string().unwrap_or_default(),
+                        repo: event["repo"].take_string().unwrap_or_default(),
+                        head_repo: event["head_repo"].take_string(),
+                        sha: event["sha"].take_string().unwrap_or_default(),
+                        branch: event["branch"].take_string().unwrap_or_default(),
+                        base_branch: event["base_branch"].take_string(),
+                        number: event["number"].as_u64(),
+                        author: event["author"].take_string(),
+                        title: event["title"].take_string(),
+                        labels: event["labels"].members()
+                            .filter_map(|label| label.as_str())
+                            .map(str::to_owned)
+                            .collect(),
+                        forge: event["forge"].as_str()
+                            .and_then(|f| f.parse().ok())
+                            .unwrap_or(Forge::GitHub),
+                        installation_id: event["installation_id"].as_u64(),
+                    },
+                    started_at: event["started_at"].as_u64()
+                        .unwrap_or_else(unix_time),
+                    queue_url: None,
+                    queued_reason: None,
+                    build_url: None,
+                    last_status: None,
+                    deadline: None,
+                });
+            },
+            Some("queue") => {
+                if let Some(tracker) = trackers.get_mut(&id) {
+                    tracker.queue_url = event["queue_url"].take_string();
+                }
+            },
+            Some("build") => {
+                if let Some(tracker) = trackers.get_mut(&id) {
+                    tracker.build_url = event["build_url"].take_string();
+                    tracker.deadline = event["deadline"].as_u64();
+                }
+            },
+            Some("status") => {
+                if let Some(tracker) = trackers.get_mut(&id) {
+                    tracker.last_status = event["state"].as_str()
+                        .and_then(|s| s.parse().ok());
+                }
+            },
+            Some("finish") => {
+                trackers.remove(&id);
+            },
+            _ => warn!(
+                "Skipping line {} of {}: unknown event",
+                i + 1,
+                path.display()
+            ),
+        }
+    }
+
+    Ok(trackers)
+}
+
+fn compact<'a, I>(path: &Path, trackers: I) -> Result<(), Box<dyn Error>>
+where I: Iterator<Item = &'a Tracker> {
+    let mut tmp_path = PathBuf::from(path);
+    tmp_path.set_extension("tmp");
+
+    {
+        let mut file = File::create(&tmp_path)?;
+
+        for tracker in trackers {
+            writeln!(file, "{}", start_event(tracker).dump())?;
+
+            if let Some(ref queue_url) = tracker.queue_url {
+                writeln!(file, "{}", queue_event(tracker.id, queue_url).dump())?;
+            }
+
+            if let (Some(build_url), Some(deadline)) =
+                (&tracker.build_url, tracker.deadline) {
+                writeln!(
+                    file,
+                    "{}",
+                    build_event(tracker.id, build_url, deadline).dump()
+                )?;
+            }
+
+            if let Some(ref status) = tracker.last_status {
+                writeln!(file, "{}", status_event(tracker.id, status).dump())?;
+            }
+        }
+
+        file.sync_all()?;
+    }
+
+    fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
+fn event(name: &str, id: u64) -> json::JsonValue {
+    let mut event = json::JsonValue::new_object();
+    event["event"] = name.into();
+    event["id"] = id.into();
+
+    event
+}
+
+fn start_event(tracker: &Tracker) -> json::JsonValue {
+    let mut event = event("start", tracker.id);
+    event["started_at"] = tracker.started_at.into();
+    event["owner"] = tracker.commit_ref.owner.as_str().into();
+    event["repo"] = tracker.commit_ref.repo.as_str().into();
+
+    if let Some(ref head_repo) = tracker.commit_ref.head_repo {
+        event["head_repo"] = head_repo.as_str().into();
+    }
+    event["sha"] = tracker.commit_ref.sha.as_str().into();
+    event["branch"] = tracker.commit_ref.branch.as_str().into();
+
+    if let Some(ref base_branch) = tracker.commit_ref.base_branch {
+        event["base_branch"] = base_branch.as_str().into();
+    }
+
+    if let Some(number) = tracker.commit_ref.number {
+        event["number"] = number.into();
+    }
+
+    if let Some(ref author) = tracker.commit_ref.author {
+        event["author"] = author.as_str().into();
+    }
+
+    if let Some(ref title) = tracker.commit_ref.title {
+        event["title"] = title.as_str().into();
+    }
+
+    if !tracker.commit_ref.labels.is_empty() {
+        event["labels"] = tracker.commit_ref.labels.iter()
+            .map(|label| label.as_str())
+            .collect::<Vec<_>>()
+            .into();
+    }
+    event["forge"] = tracker.commit_ref.forge.to_string().into();
+
+    if let Some(installation_id) = tracker.commit_ref.installation_id {
+        event["installation_id"] = installation_id.into();
+    }
+
+    event
+}
+
+fn queue_event(id: u64, queue_url: &str) -> json::JsonValue {
+    let mut event = event("queue", id);
+    event["queue_url"] = queue_url.into();
+
+    event
+}
+
+fn build_event(id: u64, build_url: &str, deadline: u64) -> json::JsonValue {
+    let mut event = event("build", id);
+    event["build_url"] = build_url.into();
+    event["deadline"] = deadline.into();
+
+    event
+}
+
+fn status_event(id: u64, status: &CommitStatus) -> json::JsonValue {
+    let mut event = event("status", id);
+    event["state"] = status.to_string().into();
+
+    event
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    fn temp_journal_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(
+            format!("kipper-store-test-{}.journal", name)
+        );
+        let _ = fs::remove_file(&path);
+
+        path
+    }
+
+    fn test_commit_ref() -> CommitRef {
+        CommitRef {
+            head_repo: Some("contributor/Hello-World".to_owned()),
+            branch: "1296-fix-the-thing".to_owned(),
+            base_branch: Some("master".to_owned()),
+            number: Some(12),
+            author: Some("contributor".to_owned()),
+            title: Some("Fix the thing".to_owned()),
+            labels: vec!["bug".to_owned()],
+            installation_id: Some(234),
+            ..CommitRef::test_default()
+        }
+    }
+
+    #[test]
+    fn open_restores_unfinished_trackers() {
+        let path = temp_journal_path("restores-unfinished");
+
+        {
+            let store = TrackerStore::open(&path)
+                .expect("Failed to open store");
+
+            let tracker = store.start(test_commit_ref())
+                .expect("Failed to start tracker");
+            store.build_queued(
+                tracker.id,
+                "http://jenkins.example.com/queue/item/41/"
+            ).expect("Failed to record queue item");
+            store.build_found(
+                tracker.id,
+                "http://jenkins.example.com/job/Hello-World-branches/3/",
+                1511352000
+            ).expect("Failed to record build");
+            store.status_posted(tracker.id, &CommitStatus::Pending)
+                .expect("Failed to record status");
+        }
+
+        let store = TrackerStore::open(&path)
+            .expect("Failed to reopen store");
+        let trackers = store.active();
+
+        assert_eq!(trackers.len(), 1);
+        assert_eq!(trackers[0].commit_ref.sha, test_commit_ref().sha);
+        assert_eq!(trackers[0].commit_ref.number, Some(12));
+        assert_eq!(trackers[0].commit_ref.installation_id, Some(234));
+        assert_eq!(
+            trackers[0].commit_ref.head_repo,
+            Some("contributor/Hello-World".to_owned())
+        );
+        assert_eq!(trackers[0].commit_ref.author, Some("contributor".to_owned()));
+        assert_eq!(trackers[0].commit_ref.title, Some("Fix the thing".to_owned()));
+        assert_eq!(trackers[0].commit_ref.labels, vec!["bug".to_owned()]);
+        assert!(trackers[0].started_at > 0);
+        assert_eq!(
+            trackers[0].commit_ref.base_branch,
+            Some("master".to_owned())
+        );
+        assert_eq!(
+            trackers[0].queue_url,
+            Some("http://jenkins.example.com/queue/item/41/".to_owned())
+        );
+        assert_eq!(
+            trackers[0].build_url,
+            Some("http://jenkins.example.com/job/Hello-World-branches/3/".to_owned())
+        );
+        assert_eq!(trackers[0].deadline, Some(1511352000));
+        assert_eq!(trackers[0].last_status, Some(CommitStatus::Pending));
+    }
+
+    #[test]
+    fn open_forgets_finished_trackers() {
+        let path = temp_journal_path("forgets-finished");
+
+        {
+            let store = TrackerStore::open(&path)
+                .expect("Failed to open store");
+
+            let finished = store.start(test_commit_ref())
+                .expect("Failed to start tracker");
+            store.start(test_commit_ref())
+                .expect("Failed to start tracker");
+            store.finish(finished.id)
+                .expect("Failed to finish tracker");
+        }
+
+        let store = TrackerStore::open(&path)
+            .expect("Failed to reopen store");
+
+        assert_eq!(store.active().len(), 1);
+        assert_eq!(store.active()[0].id, 2);
+
+        let tracker = store.start(test_commit_ref())
+            .expect("Failed to start tracker");
+
+        assert_eq!(tracker.id, 3);
+    }
+
+    #[test]
+    fn open_skips_truncated_lines() {
+        let path = temp_journal_path("skips-truncated");
+
+        fs::write(
+            &path,
+            concat!(
+                r#"{"event":"start","id":1,"owner":"octocat","repo":"Hello-World","sha":"6dcb09b","branch":"master"}"#,
+                "\n",
+                r#"{"event":"status","id":1,"sta"#
+            )
+        ).expect("Failed to write journal");
+
+        let store = TrackerStore::open(&path)
+            .expect("Failed to open store");
+
+        assert_eq!(store.active().len(), 1);
+        assert_eq!(store.active()[0].last_status, None);
+    }
+}
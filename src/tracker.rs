@@ -0,0 +1,1579 @@
+// Copyright © 2017 Teddy Wing
+//
+// This file is part of Kipper.
+//
+// Kipper is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Kipper is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with Kipper. If not, see <http://www.gnu.org/licenses/>.
+
+extern crate reqwest;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use config::Config;
+use github::CommitStatus;
+use http::{self, unix_time, HostLimiter};
+use jenkins::{self, JobStatus, Notification};
+use pull_request::CommitRef;
+use reporter::{self, Report, Reporter};
+use store::TrackerStore;
+
+// How many of a job's latest builds are searched for the commit's build.
+const BUILD_HISTORY: usize = 100;
+
+// The progress of a commit whose Jenkins build we're following. It's
+// recorded in a `TrackerStore` as it changes so tracking can resume after a
+// restart.
+#[derive(Clone, Debug)]
+pub struct Tracker {
+    pub id: u64,
+    pub commit_ref: CommitRef,
+
+    // Unix time when the webhook for the commit arrived.
+    pub started_at: u64,
+
+    // The Jenkins queue item of the build, when we triggered it ourselves.
+    pub queue_url: Option<String>,
+
+    // Why the queued build hadn't started when we last said so. It's only
+    // reported again when it changes, and isn't kept across restarts.
+    pub queued_reason: Option<String>,
+
+    pub build_url: Option<String>,
+    pub last_status: Option<CommitStatus>,
+
+    // Unix time after which we give up waiting for the build to finish.
+    pub deadline: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CancelReason {
+    // A newer commit with the given SHA was pushed to the pull request.
+    Superseded(String),
+
+    // The pull request was closed.
+    Closed,
+
+    // Jenkins notified us that the build finished, and its status has
+    // already been posted.
+    Finished,
+}
+
+// Lets the webhook handler stop a tracker, which may be waiting for its next
+// poll in the scheduler or running in a worker.
+pub struct Cancellation {
+    reason: Mutex<Option<CancelReason>>,
+    waker: Mutex<Option<Box<dyn Fn() + Send>>>,
+}
+
+impl Cancellation {
+    fn new() -> Cancellation {
+        Cancellation {
+            reason: Mutex::new(None),
+            waker: Mutex::new(None),
+        }
+    }
+
+    pub fn cancel(&self, reason: CancelReason) {
+        if let Ok(mut r) = self.reason.lock() {
+            if r.is_some() {
+                return
+            }
+
+            *r = Some(reason);
+        }
+
+        self.wake();
+    }
+
+    pub fn reason(&self) -> Option<CancelReason> {
+        self.reason.lock()
+            .ok()
+            .and_then(|r| r.clone())
+    }
+
+    // Call `waker` when the tracker is cancelled, so it can be stopped right
+    // away rather than at its next poll.
+    pub fn on_cancel<F>(&self, waker: F)
+    where F: Fn() + Send + 'static {
+        if let Ok(mut w) = self.waker.lock() {
+            *w = Some(Box::new(waker));
+        }
+
+        if self.reason().is_some() {
+            self.wake();
+        }
+    }
+
+    fn wake(&self) {
+        let waker = self.waker.lock()
+            .ok()
+            .and_then(|mut w| w.take());
+
+        if let Some(waker) = waker {
+            waker();
+        }
+    }
+}
+
+// What a newer tracker supersedes: trackers of pull requests replace each
+// other by number, and trackers of pushes by branch.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum TrackerKey {
+    PullRequest(String, String, u64),
+    Branch(String, String, String),
+}
+
+// A running tracker, as seen from other threads.
+#[derive(Clone)]
+pub struct Registration {
+    pub tracker_id: u64,
+    pub commit_ref: CommitRef,
+
+    // The tracker's build, once it has been found.
+    pub build_url: Option<String>,
+
+    pub cancellation: Arc<Cancellation>,
+}
+
+// The running trackers, so that pushing a new commit or closing a pull
+// request can stop the tracker of the old commit, and Jenkins notifications
+// can find the trackers of the commit they're about.
+#[derive(Default)]
+pub struct Registry {
+    inner: Mutex<RegistryInner>,
+}
+
+#[derive(Default)]
+struct RegistryInner {
+    trackers: HashMap<u64, Registration>,
+    latest: HashMap<TrackerKey, u64>,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    // Register a new tracker, cancelling the previous tracker of the same
+    // pull request or branch if there was one. Returns `None` if another
+    // tracker is already following the same commit, as when a label is added,
+    // a check run re-requested or a pull request's head pushed, which leaves
+    // it running.
+    pub fn register(&self, tracker: &Tracker) -> Option<Arc<Cancellation>> {
+        let cancellation = Arc::new(Cancellation::new());
+
+        if let Ok(mut inner) = self.inner.lock() {
+            let commit_ref = &tracker.commit_ref;
+            let already_tracked = inner.trackers.values().any(|r| {
+                r.commit_ref.sha == commit_ref.sha
+                    && r.commit_ref.owner == commit_ref.owner
+                    && r.commit_ref.repo == commit_ref.repo
+            });
+
+            if already_tracked {
+                return None
+            }
+
+            inner.trackers.insert(tracker.id, Registration {
+                tracker_id: tracker.id,
+                commit_ref: tracker.commit_ref.clone(),
+                build_url: tracker.build_url.clone(),
+                cancellation: cancellation.clone(),
+            });
+
+            let previous = inner.latest
+                .insert(tracker_key(&tracker.commit_ref), tracker.id)
+                .and_then(|id| inner.trackers.remove(&id));
+
+            if let Some(previous) = previous {
+                previous.cancellation.cancel(
+                    CancelReason::Superseded(tracker.commit_ref.sha.clone())
+                );
+            }
+        }
+
+        Some(cancellation)
+    }
+
+    // Stop tracking a pull request. Returns false if no tracker was running.
+    pub fn cancel(&self, owner: &str, repo: &str, number: u64) -> bool {
+        let key = TrackerKey::PullRequest(
+            owner.to_owned(),
+            repo.to_owned(),
+            number
+        );
+
+        let registration = match self.inner.lock() {
+            Ok(mut inner) => inner.latest.remove(&key)
+                .and_then(|id| inner.trackers.remove(&id)),
+            Err(_) => None,
+        };
+
+        match registration {
+            Some(registration) => {
+                registration.cancellation.cancel(CancelReason::Closed);
+
+                true
+            },
+            None => false,
+        }
+    }
+
+    // The running trackers of a commit. There can be more than one if the
+    // commit was pushed to several repositories.
+    pub fn find_commit(&self, sha: &str) -> Vec<Registration> {
+        match self.inner.lock() {
+            Ok(inner) => inner.trackers.values()
+                .filter(|r| r.commit_ref.sha == sha)
+                .cloned()
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    // Record the build a tracker found, so that notifications about other
+    // builds of its commit are left out.
+    pub fn build_found(&self, tracker: &Tracker) {
+        if let Ok(mut inner) = self.inner.lock() {
+            if let Some(registration) = inner.trackers.get_mut(&tracker.id) {
+                registration.build_url = tracker.build_url.clone();
+            }
+        }
+    }
+
+    // Remove a finished tracker, leaving a newer tracker of the same pull
+    // request or branch in place.
+    pub fn deregister(&self, tracker: &Tracker) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.trackers.remove(&tracker.id);
+
+            let key = tracker_key(&tracker.commit_ref);
+
+            if inner.latest.get(&key) == Some(&tracker.id) {
+                inner.latest.remove(&key);
+            }
+        }
+    }
+}
+
+fn tracker_key(commit_ref: &CommitRef) -> TrackerKey {
+    let owner = commit_ref.owner.clone();
+    let repo = commit_ref.repo.clone();
+
+    match commit_ref.number {
+        Some(number) => TrackerKey::PullRequest(owner, repo, number),
+        None => TrackerKey::Branch(owner, repo, commit_ref.branch.clone()),
+    }
+}
+
+// Take a tracker one step further: look for its build if it hasn't been
+// found yet, and report the build's status if it changed. Returns how long to
+// wait before the next step, or `None` once the tracker is done and has been
+// removed from `store` and `registry`.
+pub fn advance(
+    tracker: &mut Tracker,
+    config: &Config,
+    store: &TrackerStore,
+    registry: &Registry,
+    cancellation: &Cancellation,
+    jenkins_limiter: &HostLimiter,
+) -> Option<Duration> {
+    let had_build = tracker.build_url.is_some();
+
+    let result = reporter::for_commit(config, &tracker.commit_ref)
+        .and_then(|reporter| {
+            match step(tracker, config, store, reporter.as_ref(), cancellation, jenkins_limiter) {
+                Ok(Step::Wait(delay)) => Ok(Some(delay)),
+                Ok(Step::Done(Some(reason))) => {
+                    cancelled(tracker, config, reporter.as_ref(), reason)
+                        .map(|_| None)
+                },
+                Ok(Step::Done(None)) => Ok(None),
+                Err(e) => Err(e),
+            }
+        });
+
+    if !had_build && tracker.build_url.is_some() {
+        registry.build_found(tracker);
+    }
+
+    match result {
+        Ok(Some(delay)) => return Some(delay),
+        Ok(None) => (),
+        Err(e) => error!("{}", e.to_string()),
+    }
+
+    registry.deregister(tracker);
+
+    if let Err(e) = store.finish(tracker.id) {
+        error!("{}", e.to_string());
+    }
+
+    None
+}
+
+enum Step {
+    // Check on the build again after this long.
+    Wait(Duration),
+
+    // Stop tracking, with the reason if the tracker was cancelled.
+    Done(Option<CancelReason>),
+}
+
+fn step(
+    tracker: &mut Tracker,
+    config: &Config,
+    store: &TrackerStore,
+    reporter: &dyn Reporter,
+    cancellation: &Cancellation,
+    jenkins_limiter: &HostLimiter,
+) -> Result<Step, Box<dyn Error>> {
+    if let Some(reason) = cancellation.reason() {
+        return Ok(Step::Done(Some(reason)))
+    }
+
+    let repo_config = config.repo(&tracker.commit_ref.owner, &tracker.commit_ref.repo);
+
+    let jenkins_client = jenkins::jenkins_request_client(
+        &config.jenkins.user_id,
+        &config.jenkins.token.expose().to_owned()
+    )?.limited_by(jenkins_limiter);
+
+    // Let the pull request show that a build is expected while Jenkins gets
+    // round to starting one.
+    if tracker.build_url.is_none()
+        && tracker.last_status.is_none()
+        && !repo_config.trigger {
+        reporter.report(
+            &tracker.commit_ref,
+            &Report {
+                status: CommitStatus::Pending,
+                build_url: None,
+                description: Some("Waiting for Jenkins to start a build.".to_owned()),
+            }
+        )?;
+
+        store.status_posted(tracker.id, &CommitStatus::Pending)?;
+        tracker.last_status = Some(CommitStatus::Pending);
+
+        return Ok(Step::Wait(Duration::from_secs(repo_config.warm_up)))
+    }
+
+    let (build_url, found) = match tracker.build_url.clone() {
+        Some(url) => (url, false),
+        None => {
+            match locate_build(tracker, config, store, &jenkins_client)? {
+                Located::Found(url) => (url, true),
+                Located::Queued(why) => {
+                    let now = unix_time();
+                    let give_up_at = tracker.started_at + repo_config.discovery_timeout;
+
+                    if now >= give_up_at {
+                        reporter.report(
+                            &tracker.commit_ref,
+                            &Report {
+                                status: CommitStatus::Error,
+                                build_url: None,
+                                description: Some(status_description(format!(
+                                    "The build was still queued after {}.",
+                                    duration_description(repo_config.discovery_timeout)
+                                ))),
+                            }
+                        )?;
+
+                        store.status_posted(tracker.id, &CommitStatus::Error)?;
+
+                        return Ok(Step::Done(None))
+                    }
+
+                    if tracker.last_status.is_none()
+                        || tracker.queued_reason != why {
+                        reporter.report(
+                            &tracker.commit_ref,
+                            &Report {
+                                status: CommitStatus::Pending,
+                                build_url: None,
+                                description: Some(queued_description(why.as_ref())),
+                            }
+                        )?;
+
+                        store.status_posted(tracker.id, &CommitStatus::Pending)?;
+                        tracker.last_status = Some(CommitStatus::Pending);
+                        tracker.queued_reason = why;
+                    }
+
+                    return Ok(Step::Wait(Duration::from_secs(
+                        repo_config.poll_interval.min(give_up_at - now)
+                    )))
+                },
+                Located::Cancelled => {
+                    reporter.report(
+                        &tracker.commit_ref,
+                        &Report {
+                            status: CommitStatus::Error,
+                            build_url: None,
+                            description: Some(
+                                "The build was cancelled before it started."
+                                    .to_owned()
+                            ),
+                        }
+                    )?;
+
+                    store.status_posted(tracker.id, &CommitStatus::Error)?;
+
+                    return Ok(Step::Done(None))
+                },
+                Located::Missing => {
+                    let now = unix_time();
+                    let give_up_at = tracker.started_at + repo_config.discovery_timeout;
+
+                    // Look again after as long as we've already been
+                    // waiting, so that slow starts don't mean many requests.
+                    if now < give_up_at {
+                        let waited = now.saturating_sub(tracker.started_at);
+                        let delay = waited.max(repo_config.poll_interval)
+                            .min(give_up_at - now);
+
+                        debug!("No build found yet, looking again in {}s", delay);
+
+                        return Ok(Step::Wait(Duration::from_secs(delay)))
+                    }
+
+                    let matcher = repo_config.job_matcher()?;
+
+                    reporter.report(
+                        &tracker.commit_ref,
+                        &Report {
+                            status: CommitStatus::Error,
+                            build_url: None,
+                            description: Some(status_description(format!(
+                                "No Jenkins build {} was found.",
+                                matcher.describe(&tracker.commit_ref)
+                            ))),
+                        }
+                    )?;
+
+                    store.status_posted(tracker.id, &CommitStatus::Error)?;
+
+                    return Ok(Step::Done(None))
+                },
+            }
+        },
+    };
+
+    let job = jenkins::request_job(
+        &config.jenkins.url,
+        &jenkins_client,
+        &build_url
+    )?;
+
+    if found {
+        let deadline = unix_time() + repo_config.timeout_for(&job);
+
+        store.build_found(tracker.id, &build_url, deadline)?;
+        tracker.build_url = Some(build_url.clone());
+        tracker.deadline = Some(deadline);
+    }
+
+    let commit_status = repo_config.commit_status(&job.result);
+
+    // A "queued" status is replaced once the build starts, even though it's
+    // also pending.
+    if found || tracker.last_status.as_ref() != Some(&commit_status) {
+        reporter.report(
+            &tracker.commit_ref,
+            &Report {
+                status: commit_status.clone(),
+                build_url: Some(build_url.clone()),
+                description: Some(job.description()),
+            }
+        )?;
+
+        store.status_posted(tracker.id, &commit_status)?;
+        tracker.last_status = Some(commit_status);
+    }
+
+    if job.result != JobStatus::Pending {
+        return Ok(Step::Done(None))
+    }
+
+    debug!("Waiting for job to finish");
+
+    if tracker.deadline.is_some_and(|d| unix_time() >= d) {
+        reporter.report(
+            &tracker.commit_ref,
+            &Report {
+                status: CommitStatus::Error,
+                build_url: Some(build_url),
+                description: Some("The status checker timed out.".to_owned()),
+            }
+        )?;
+
+        store.status_posted(tracker.id, &CommitStatus::Error)?;
+
+        return Ok(Step::Done(None))
+    }
+
+    Ok(Step::Wait(Duration::from_secs(repo_config.poll_interval)))
+}
+
+// Post the status of a build Jenkins told us about on the commits being
+// tracked for it, returning how many there were. Trackers whose build has
+// finished are stopped. Until then they keep polling, in case a notification
+// gets lost.
+//
+// Other jobs may build the same commit, so a tracker only takes the
+// notification if it's about the build it found, or before then, a build of
+// the job its build is looked for in.
+pub fn notify(
+    notification: &Notification,
+    config: &Config,
+    store: &TrackerStore,
+    registry: &Registry,
+) -> Result<usize, Box<dyn Error>> {
+    let mut registrations = Vec::new();
+
+    for registration in registry.find_commit(&notification.sha) {
+        let repo_config = config.repo(
+            &registration.commit_ref.owner,
+            &registration.commit_ref.repo
+        );
+
+        let tracked = match registration.build_url {
+            Some(ref url) => *url == notification.build_url,
+            None => repo_config.job_locator()?
+                .job_path(&registration.commit_ref)
+                .is_some_and(|path| {
+                    is_build_of(&notification.build_url, &config.jenkins.url, &path)
+                }),
+        };
+
+        if tracked {
+            registrations.push(registration);
+        }
+    }
+
+    for registration in &registrations {
+        let commit_status = config.repo(
+            &registration.commit_ref.owner,
+            &registration.commit_ref.repo
+        ).commit_status(&notification.result);
+
+        reporter::for_commit(config, &registration.commit_ref)?.report(
+            &registration.commit_ref,
+            &Report {
+                status: commit_status.clone(),
+                build_url: Some(notification.build_url.clone()),
+                description: Some(notification.result.description().to_owned()),
+            }
+        )?;
+
+        store.status_posted(registration.tracker_id, &commit_status)?;
+
+        if notification.result != JobStatus::Pending {
+            registration.cancellation.cancel(CancelReason::Finished);
+        }
+    }
+
+    Ok(registrations.len())
+}
+
+fn cancelled(
+    tracker: &Tracker,
+    config: &Config,
+    reporter: &dyn Reporter,
+    reason: CancelReason,
+) -> Result<(), Box<dyn Error>> {
+    info!(
+        "Stopped tracking {}/{} {}: {:?}",
+        tracker.commit_ref.owner,
+        tracker.commit_ref.repo,
+        tracker.commit_ref.sha,
+        reason
+    );
+
+    let newer_sha = match reason {
+        CancelReason::Superseded(sha) => sha,
+        CancelReason::Closed | CancelReason::Finished => return Ok(()),
+    };
+
+    if !config.github.report_superseded {
+        return Ok(())
+    }
+
+    let (short_sha, _) = newer_sha.split_at(newer_sha.len().min(7));
+
+    reporter.report(
+        &tracker.commit_ref,
+        &Report {
+            status: CommitStatus::Error,
+            build_url: tracker.build_url.clone(),
+            description: Some(
+                format!("Superseded by {}, no longer tracked.", short_sha)
+            ),
+        }
+    )
+}
+
+// Where the commit's build has got to, before we start following it.
+enum Located {
+    Found(String),
+
+    // Waiting in the Jenkins queue, for the given reason.
+    Queued(Option<String>),
+
+    // Removed from the queue without being run.
+    Cancelled,
+
+    Missing,
+}
+
+fn locate_build(
+    tracker: &mut Tracker,
+    config: &Config,
+    store: &TrackerStore,
+    jenkins_client: &http::Client,
+) -> Result<Located, Box<dyn Error>> {
+    let repo_config = config.repo(&tracker.commit_ref.owner, &tracker.commit_ref.repo);
+
+    if tracker.queue_url.is_none() && !repo_config.trigger {
+        if let Some(url) = find_build(&tracker.commit_ref, config, jenkins_client)? {
+            return Ok(Located::Found(url))
+        }
+    }
+
+    follow_queue(tracker, config, store, jenkins_client)
+}
+
+// Trigger a build of the commit, or look for one in the Jenkins queue, if we
+// haven't yet, then check whether it has left the queue.
+fn follow_queue(
+    tracker: &mut Tracker,
+    config: &Config,
+    store: &TrackerStore,
+    jenkins_client: &http::Client,
+) -> Result<Located, Box<dyn Error>> {
+    let queue_url = match tracker.queue_url.clone() {
+        Some(url) => url,
+        None => {
+            let repo_config = config.repo(
+                &tracker.commit_ref.owner,
+                &tracker.commit_ref.repo
+            );
+
+            let job_path = match repo_config.job_locator()?
+                .job_path(&tracker.commit_ref) {
+                Some(p) => p,
+                None => return Ok(Located::Missing),
+            };
+
+            let url = if repo_config.trigger {
+                jenkins::trigger_build(
+                    &config.jenkins.url,
+                    jenkins_client,
+                    &job_path,
+                    &tracker.commit_ref
+                )?
+            } else {
+                match jenkins::find_queue_item(
+                    &config.jenkins.url,
+                    jenkins_client,
+                    &job_path,
+                    &tracker.commit_ref
+                )? {
+                    Some(url) => url,
+                    None => return Ok(Located::Missing),
+                }
+            };
+
+            debug!("Build queued: {}", url);
+
+            store.build_queued(tracker.id, &url)?;
+            tracker.queue_url = Some(url.clone());
+
+            url
+        },
+    };
+
+    let item = jenkins::get_queue_item(
+        &config.jenkins.url,
+        jenkins_client,
+        &queue_url
+    )?;
+
+    if item.cancelled {
+        return Ok(Located::Cancelled)
+    }
+
+    match item.build_url {
+        Some(url) => {
+            debug!("Job found: {}", url);
+
+            Ok(Located::Found(url))
+        },
+        None => Ok(Located::Queued(item.why)),
+    }
+}
+
+// Whether `build_url` is a build of the job at `job_path` on the Jenkins at
+// `jenkins_url`, like `http://jenkins.example.com/job/Hello-World-branches/3/`
+// of `/job/Hello-World-branches`.
+fn is_build_of(build_url: &str, jenkins_url: &str, job_path: &str) -> bool {
+    let (build_url, jenkins_url) = match (
+        reqwest::Url::parse(build_url),
+        reqwest::Url::parse(jenkins_url),
+    ) {
+        (Ok(b), Ok(j)) => (b, j),
+        _ => return false,
+    };
+
+    let job = format!("{}{}", jenkins_url.path().trim_end_matches('/'), job_path);
+
+    match build_url.path().trim_end_matches('/').rsplit_once('/') {
+        Some((path, number)) => {
+            path == job
+                && !number.is_empty()
+                && number.bytes().all(|b| b.is_ascii_digit())
+        },
+        None => false,
+    }
+}
+
+fn queued_description(why: Option<&String>) -> String {
+    status_description(match why {
+        Some(why) => format!(
+            "Queued: waiting for executor ({})",
+            why.trim_end_matches('.')
+        ),
+        None => "Queued: waiting for executor".to_owned(),
+    })
+}
+
+// E.g. "10 minutes" or "90 seconds".
+fn duration_description(seconds: u64) -> String {
+    match seconds {
+        1 => "1 second".to_owned(),
+        60 => "1 minute".to_owned(),
+        s if s % 60 == 0 => format!("{} minutes", s / 60),
+        s => format!("{} seconds", s),
+    }
+}
+
+// GitHub limits status descriptions to 140 characters.
+fn status_description(description: String) -> String {
+    description.chars().take(140).collect()
+}
+
+// The URL of the commit's build, looked for among the most recent builds of
+// its job.
+fn find_build(
+    commit_ref: &CommitRef,
+    config: &Config,
+    jenkins_client: &http::Client,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let repo_config = config.repo(&commit_ref.owner, &commit_ref.repo);
+    let matcher = repo_config.job_matcher()?;
+
+    let job_path = match repo_config.job_locator()?.job_path(commit_ref) {
+        Some(p) => p,
+        None => return Ok(None),
+    };
+
+    let jobs = jenkins::get_jobs(
+        &config.jenkins.url,
+        jenkins_client,
+        &job_path,
+        BUILD_HISTORY
+    )?;
+
+    let build_url = jobs.into_iter()
+        .find(|job| jenkins::job_for_commit(job, commit_ref, matcher.as_ref()))
+        .map(|job| job.url);
+
+    if let Some(ref url) = build_url {
+        debug!("Job found: {}", url);
+    }
+
+    Ok(build_url)
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate mockito;
+
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::fs;
+
+    use self::mockito::{mock, Matcher};
+
+    use config::{GitHubConfig, JenkinsConfig, RepoConfig, Secret};
+
+    use super::*;
+
+    fn test_config() -> Config {
+        Config {
+            port: 8000,
+            state_file: None,
+            workers: 1,
+            queue_limit: 10,
+            jenkins: JenkinsConfig {
+                url: mockito::SERVER_URL.to_owned(),
+                user_id: "username".to_owned(),
+                token: Secret::new("token".to_owned()),
+                notification_token: None,
+                max_concurrent_requests: 1,
+            },
+            github: GitHubConfig {
+                api_url: mockito::SERVER_URL.to_owned(),
+                token: Some(Secret::new("token".to_owned())),
+                app: None,
+                webhook_secret: None,
+                report_superseded: false,
+            },
+            gitlab: None,
+            repos: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn tracking_resumes_after_restart() {
+        let path = env::temp_dir().join("kipper-tracker-test-resume.journal");
+        let _ = fs::remove_file(&path);
+
+        let build_url = format!(
+            "{}/job/resumed-repo-branches/7/",
+            mockito::SERVER_URL
+        );
+
+        // First run: the build is found and reported as pending, then
+        // Kipper is killed before the build finishes.
+        {
+            let store = TrackerStore::open(&path)
+                .expect("Failed to open store");
+
+            let tracker = store.start(CommitRef {
+                owner: "resumed-owner".to_owned(),
+                repo: "resumed-repo".to_owned(),
+                sha: "a3c1d2e4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9c0".to_owned(),
+                branch: "8712-resume-tracking".to_owned(),
+                ..CommitRef::test_default()
+            }).expect("Failed to start tracker");
+
+            store.build_found(tracker.id, &build_url, unix_time() + 20 * 60)
+                .expect("Failed to record build");
+            store.status_posted(tracker.id, &CommitStatus::Pending)
+                .expect("Failed to record status");
+        }
+
+        let _jenkins = mock("GET", "/job/resumed-repo-branches/7/api/json")
+            .with_status(200)
+            .with_header("content-type", "application/json;charset=utf-8")
+            .with_body(r#"
+                {
+                  "displayName": "8712-resume-tracking-a3c1d",
+                  "result": "SUCCESS"
+                }
+            "#)
+            .create();
+
+        let github = mock(
+                "POST",
+                "/repos/resumed-owner/resumed-repo/statuses/a3c1d2e4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9c0"
+            )
+            .match_body(Matcher::Regex(r#""state":"success""#.to_owned()))
+            .with_status(201)
+            .create();
+
+        // Second run: the tracker is picked up from the journal.
+        let store = TrackerStore::open(&path)
+            .expect("Failed to reopen store");
+
+        assert_eq!(store.active().len(), 1);
+
+        let mut tracker = store.active()[0].clone();
+
+        assert_eq!(tracker.build_url, Some(build_url));
+
+        assert_eq!(
+            advance(
+                &mut tracker,
+                &test_config(),
+                &store,
+                &Registry::new(),
+                &Cancellation::new(),
+                &HostLimiter::new(1)
+            ),
+            None
+        );
+
+        github.assert();
+
+        let store = TrackerStore::open(&path)
+            .expect("Failed to reopen store");
+
+        assert!(store.active().is_empty());
+    }
+
+    #[test]
+    fn triggered_build_is_followed_from_the_queue() {
+        let mut config = test_config();
+        config.jenkins.url = format!("{}/triggered", mockito::SERVER_URL);
+        config.repos.insert(
+            "triggered-owner/triggered-repo".to_owned(),
+            RepoConfig {
+                trigger: true,
+                ..RepoConfig::default()
+            }
+        );
+
+        let store = TrackerStore::in_memory();
+        let mut tracker = store.start(CommitRef {
+            owner: "triggered-owner".to_owned(),
+            repo: "triggered-repo".to_owned(),
+            sha: "c0ffee5b57875f334f61aebed695e2e4193db5e6".to_owned(),
+            branch: "5512-trigger-it".to_owned(),
+            base_branch: Some("master".to_owned()),
+            number: Some(3),
+            ..CommitRef::test_default()
+        }).expect("Failed to start tracker");
+
+        let _crumb = mock("GET", "/triggered/crumbIssuer/api/json")
+            .with_status(404)
+            .create();
+
+        let trigger = mock(
+                "POST",
+                "/triggered/job/triggered-repo-branches/buildWithParameters?branch=5512-trigger-it&sha1=c0ffee5b57875f334f61aebed695e2e4193db5e6&number=3&base_branch=master"
+            )
+            .with_status(201)
+            .with_header("location", "http://jenkins.example.com/queue/item/51/")
+            .expect(1)
+            .create();
+
+        let waiting = mock("GET", "/triggered/queue/item/51/api/json")
+            .with_status(200)
+            .with_body(r#"
+                {
+                  "cancelled": false,
+                  "executable": null,
+                  "why": "Waiting for next available executor on linux."
+                }
+            "#)
+            .create();
+
+        let queued = mock(
+                "POST",
+                "/repos/triggered-owner/triggered-repo/statuses/c0ffee5b57875f334f61aebed695e2e4193db5e6"
+            )
+            .match_body(Matcher::Regex(
+                r#""description":"Queued: waiting for executor \(Waiting for next available executor on linux\)""#.to_owned()
+            ))
+            .with_status(201)
+            .create();
+
+        // The build is queued and hasn't started yet.
+        assert_eq!(
+            advance(
+                &mut tracker,
+                &config,
+                &store,
+                &Registry::new(),
+                &Cancellation::new(),
+                &HostLimiter::new(1)
+            ),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            tracker.queue_url,
+            Some("http://jenkins.example.com/queue/item/51/".to_owned())
+        );
+        assert_eq!(tracker.build_url, None);
+
+        queued.assert();
+        drop(waiting);
+
+        let _started = mock("GET", "/triggered/queue/item/51/api/json")
+            .with_status(200)
+            .with_body(r#"
+                {
+                  "cancelled": false,
+                  "executable": {
+                    "number": 19,
+                    "url": "http://jenkins.example.com/job/triggered-repo-branches/19/"
+                  }
+                }
+            "#)
+            .create();
+
+        let _build = mock("GET", "/triggered/job/triggered-repo-branches/19/api/json")
+            .with_status(200)
+            .with_body(r##"{"displayName": "#19", "result": "SUCCESS"}"##)
+            .create();
+
+        let github = mock(
+                "POST",
+                "/repos/triggered-owner/triggered-repo/statuses/c0ffee5b57875f334f61aebed695e2e4193db5e6"
+            )
+            .match_body(Matcher::Regex(r#""state":"success""#.to_owned()))
+            .with_status(201)
+            .create();
+
+        assert_eq!(
+            advance(
+                &mut tracker,
+                &config,
+                &store,
+                &Registry::new(),
+                &Cancellation::new(),
+                &HostLimiter::new(1)
+            ),
+            None
+        );
+
+        trigger.assert();
+        github.assert();
+    }
+
+    #[test]
+    fn queued_build_is_found_by_its_commit() {
+        let store = TrackerStore::in_memory();
+        let mut tracker = store.start(CommitRef {
+            owner: "queued-owner".to_owned(),
+            repo: "queued-repo".to_owned(),
+            sha: "d3adb33f57875f334f61aebed695e2e4193db5e6".to_owned(),
+            branch: "6620-busy-jenkins".to_owned(),
+            ..CommitRef::test_default()
+        }).expect("Failed to start tracker");
+
+        let _builds = mock(
+                "GET",
+                Matcher::Regex(r"^/job/queued-repo-branches/api/json\?".to_owned())
+            )
+            .with_status(200)
+            .with_body(r#"{"builds": []}"#)
+            .create();
+
+        let _queue = mock("GET", Matcher::Regex(r"^/queue/api/json\?".to_owned()))
+            .with_status(200)
+            .with_body(r#"
+                {
+                  "items": [
+                    {
+                      "actions": [
+                        {
+                          "parameters": [
+                            { "name": "sha1", "value": "d3adb33f57875f334f61aebed695e2e4193db5e6" }
+                          ]
+                        }
+                      ],
+                      "task": { "url": "http://jenkins.example.com/job/queued-repo-branches/" },
+                      "url": "queue/item/88/",
+                      "why": "Waiting for next available executor"
+                    }
+                  ]
+                }
+            "#)
+            .create();
+
+        let _item = mock("GET", "/queue/item/88/api/json")
+            .with_status(200)
+            .with_body(r#"
+                {
+                  "cancelled": false,
+                  "executable": null,
+                  "why": "Waiting for next available executor"
+                }
+            "#)
+            .create();
+
+        let waiting = mock(
+                "POST",
+                "/repos/queued-owner/queued-repo/statuses/d3adb33f57875f334f61aebed695e2e4193db5e6"
+            )
+            .match_body(Matcher::Regex(
+                r#""description":"Waiting for Jenkins to start a build.""#.to_owned()
+            ))
+            .with_status(201)
+            .create();
+
+        let github = mock(
+                "POST",
+                "/repos/queued-owner/queued-repo/statuses/d3adb33f57875f334f61aebed695e2e4193db5e6"
+            )
+            .match_body(Matcher::Regex(
+                r#""description":"Queued: waiting for executor \(Waiting for next available executor\)""#.to_owned()
+            ))
+            .with_status(201)
+            .expect(1)
+            .create();
+
+        // A pending status is posted before the warm-up.
+        assert_eq!(
+            advance(
+                &mut tracker,
+                &test_config(),
+                &store,
+                &Registry::new(),
+                &Cancellation::new(),
+                &HostLimiter::new(1)
+            ),
+            Some(Duration::from_secs(30))
+        );
+        waiting.assert();
+
+        for _ in 0..2 {
+            assert_eq!(
+                advance(
+                    &mut tracker,
+                    &test_config(),
+                    &store,
+                    &Registry::new(),
+                    &Cancellation::new(),
+                    &HostLimiter::new(1)
+                ),
+                Some(Duration::from_secs(30))
+            );
+        }
+
+        // The status isn't posted again while the reason stays the same.
+        github.assert();
+        assert_eq!(
+            tracker.queue_url,
+            Some(format!("{}/queue/item/88/", mockito::SERVER_URL))
+        );
+    }
+
+    #[test]
+    fn missing_build_is_reported_after_discovery_timeout() {
+        let mut config = test_config();
+        config.jenkins.url = format!("{}/missing", mockito::SERVER_URL);
+
+        let store = TrackerStore::in_memory();
+        let mut tracker = store.start(CommitRef {
+            owner: "missing-owner".to_owned(),
+            repo: "missing-repo".to_owned(),
+            sha: "5eed1e57875f334f61aebed695e2e4193db5e6a1".to_owned(),
+            branch: "4410-forgot-to-rename".to_owned(),
+            ..CommitRef::test_default()
+        }).expect("Failed to start tracker");
+        tracker.last_status = Some(CommitStatus::Pending);
+
+        let _builds = mock(
+                "GET",
+                Matcher::Regex(r"^/missing/job/missing-repo-branches/api/json\?".to_owned())
+            )
+            .with_status(200)
+            .with_body(r##"{"builds": [{"displayName": "#12", "url": "http://jenkins.example.com/job/missing-repo-branches/12/"}]}"##)
+            .create();
+
+        let _queue = mock("GET", Matcher::Regex(r"^/missing/queue/api/json\?".to_owned()))
+            .with_status(200)
+            .with_body(r#"{"items": []}"#)
+            .create();
+
+        // Shortly after the webhook, we keep looking.
+        tracker.started_at = unix_time() - 40;
+
+        match advance(
+            &mut tracker,
+            &config,
+            &store,
+            &Registry::new(),
+            &Cancellation::new(),
+            &HostLimiter::new(1)
+        ) {
+            Some(delay) => assert!(delay >= Duration::from_secs(40)),
+            None => panic!("Stopped looking for the build"),
+        }
+
+        let github = mock(
+                "POST",
+                "/repos/missing-owner/missing-repo/statuses/5eed1e57875f334f61aebed695e2e4193db5e6a1"
+            )
+            .match_body(Matcher::Regex(
+                r#""description":"No Jenkins build of 5eed1e5 or named `4410-forgot-to-rename-5eed1` was found.""#.to_owned()
+            ))
+            .with_status(201)
+            .create();
+
+        tracker.started_at = unix_time() - 11 * 60;
+
+        assert_eq!(
+            advance(
+                &mut tracker,
+                &config,
+                &store,
+                &Registry::new(),
+                &Cancellation::new(),
+                &HostLimiter::new(1)
+            ),
+            None
+        );
+
+        github.assert();
+    }
+
+    #[test]
+    fn queued_build_is_reported_after_discovery_timeout() {
+        let mut config = test_config();
+        config.jenkins.url = format!("{}/stuck", mockito::SERVER_URL);
+
+        let store = TrackerStore::in_memory();
+        let mut tracker = store.start(CommitRef {
+            owner: "stuck-owner".to_owned(),
+            repo: "stuck-repo".to_owned(),
+            sha: "57c4ed1e57875f334f61aebed695e2e4193db5e6".to_owned(),
+            branch: "6021-no-executors".to_owned(),
+            ..CommitRef::test_default()
+        }).expect("Failed to start tracker");
+        tracker.last_status = Some(CommitStatus::Pending);
+        tracker.queued_reason = Some("Waiting for next available executor".to_owned());
+        tracker.queue_url = Some("http://jenkins.example.com/queue/item/61/".to_owned());
+
+        // The queue item never gets an `executable`.
+        let _waiting = mock("GET", "/stuck/queue/item/61/api/json")
+            .with_status(200)
+            .with_body(r#"
+                {
+                  "cancelled": false,
+                  "executable": null,
+                  "why": "Waiting for next available executor"
+                }
+            "#)
+            .create();
+
+        // Shortly after the webhook, we keep waiting, but not past the
+        // discovery timeout.
+        tracker.started_at = unix_time() - (10 * 60 - 5);
+
+        match advance(
+            &mut tracker,
+            &config,
+            &store,
+            &Registry::new(),
+            &Cancellation::new(),
+            &HostLimiter::new(1)
+        ) {
+            Some(delay) => assert!(delay <= Duration::from_secs(5)),
+            None => panic!("Stopped waiting for the queued build"),
+        }
+
+        let github = mock(
+                "POST",
+                "/repos/stuck-owner/stuck-repo/statuses/57c4ed1e57875f334f61aebed695e2e4193db5e6"
+            )
+            .match_body(Matcher::Regex(
+                r#""description":"The build was still queued after 10 minutes.""#.to_owned()
+            ))
+            .with_status(201)
+            .create();
+
+        tracker.started_at = unix_time() - 11 * 60;
+
+        assert_eq!(
+            advance(
+                &mut tracker,
+                &config,
+                &store,
+                &Registry::new(),
+                &Cancellation::new(),
+                &HostLimiter::new(1)
+            ),
+            None
+        );
+
+        github.assert();
+    }
+
+    fn pull_request_tracker(id: u64, sha: &str) -> Tracker {
+        Tracker {
+            id,
+            commit_ref: CommitRef {
+                sha: sha.to_owned(),
+                branch: "3318-push-often".to_owned(),
+                number: Some(9),
+                ..CommitRef::test_default()
+            },
+            started_at: 0,
+            queue_url: None,
+            queued_reason: None,
+            build_url: None,
+            last_status: None,
+            deadline: None,
+        }
+    }
+
+    #[test]
+    fn registry_register_cancels_previous_tracker_of_pull_request() {
+        let registry = Registry::new();
+
+        let first = registry.register(&pull_request_tracker(1, "5c6e1b2"))
+            .expect("Failed to register tracker");
+        let second = registry.register(&pull_request_tracker(2, "9a0f3d4"))
+            .expect("Failed to register tracker");
+
+        assert_eq!(
+            first.reason(),
+            Some(CancelReason::Superseded("9a0f3d4".to_owned()))
+        );
+        assert_eq!(second.reason(), None);
+    }
+
+    #[test]
+    fn registry_register_keeps_tracker_of_same_commit() {
+        let registry = Registry::new();
+
+        let first = registry.register(&pull_request_tracker(1, "5c6e1b2"))
+            .expect("Failed to register tracker");
+
+        // The pull request is labelled while its head is being tracked.
+        assert!(registry.register(&pull_request_tracker(2, "5c6e1b2")).is_none());
+
+        assert_eq!(first.reason(), None);
+        assert_eq!(registry.find_commit("5c6e1b2").len(), 1);
+        assert_eq!(registry.find_commit("5c6e1b2")[0].tracker_id, 1);
+    }
+
+    #[test]
+    fn registry_register_cancels_previous_tracker_of_branch() {
+        let registry = Registry::new();
+
+        let mut first = pull_request_tracker(1, "5c6e1b2");
+        first.commit_ref.number = None;
+        let first = registry.register(&first)
+            .expect("Failed to register tracker");
+
+        let mut second = pull_request_tracker(2, "9a0f3d4");
+        second.commit_ref.number = None;
+        let second = registry.register(&second)
+            .expect("Failed to register tracker");
+
+        assert_eq!(
+            first.reason(),
+            Some(CancelReason::Superseded("9a0f3d4".to_owned()))
+        );
+        assert_eq!(second.reason(), None);
+    }
+
+    #[test]
+    fn registry_register_skips_push_of_tracked_pull_request() {
+        let registry = Registry::new();
+
+        let first = registry.register(&pull_request_tracker(1, "5c6e1b2"))
+            .expect("Failed to register tracker");
+
+        let mut push = pull_request_tracker(2, "5c6e1b2");
+        push.commit_ref.number = None;
+
+        assert!(registry.register(&push).is_none());
+        assert_eq!(first.reason(), None);
+        assert_eq!(registry.find_commit("5c6e1b2").len(), 1);
+    }
+
+    #[test]
+    fn registry_cancel_stops_tracker_of_closed_pull_request() {
+        let registry = Registry::new();
+
+        let cancellation = registry.register(&pull_request_tracker(1, "5c6e1b2"))
+            .expect("Failed to register tracker");
+
+        assert!(registry.cancel("octocat", "Hello-World", 9));
+        assert_eq!(cancellation.reason(), Some(CancelReason::Closed));
+        assert!(!registry.cancel("octocat", "Hello-World", 9));
+    }
+
+    #[test]
+    fn registry_deregister_keeps_newer_tracker() {
+        let registry = Registry::new();
+
+        let first = pull_request_tracker(1, "5c6e1b2");
+        registry.register(&first);
+        let second = registry.register(&pull_request_tracker(2, "9a0f3d4"))
+            .expect("Failed to register tracker");
+
+        registry.deregister(&first);
+
+        assert!(registry.cancel("octocat", "Hello-World", 9));
+        assert_eq!(second.reason(), Some(CancelReason::Closed));
+    }
+
+    #[test]
+    fn cancellation_calls_waker_once() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let wake_ups = Arc::new(AtomicUsize::new(0));
+        let cancellation = Cancellation::new();
+
+        let w = wake_ups.clone();
+        cancellation.on_cancel(move || {
+            w.fetch_add(1, Ordering::SeqCst);
+        });
+
+        cancellation.cancel(CancelReason::Closed);
+        cancellation.cancel(CancelReason::Finished);
+
+        assert_eq!(wake_ups.load(Ordering::SeqCst), 1);
+        assert_eq!(cancellation.reason(), Some(CancelReason::Closed));
+
+        // A waker added after cancelling is called straight away.
+        let late = Cancellation::new();
+        late.cancel(CancelReason::Closed);
+
+        let w = wake_ups.clone();
+        late.on_cancel(move || {
+            w.fetch_add(1, Ordering::SeqCst);
+        });
+
+        assert_eq!(wake_ups.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn superseded_tracker_reports_newer_commit() {
+        let mut config = test_config();
+        config.github.report_superseded = true;
+
+        let mut tracker = pull_request_tracker(1, "e7d3b1a2c4f5e6d7c8b9a0f1e2d3c4b5a6f7e8d9");
+        tracker.commit_ref.owner = "superseded-owner".to_owned();
+
+        let github = mock(
+                "POST",
+                "/repos/superseded-owner/Hello-World/statuses/e7d3b1a2c4f5e6d7c8b9a0f1e2d3c4b5a6f7e8d9"
+            )
+            .match_body(
+                Matcher::Regex("Superseded by 0b1c2d3".to_owned())
+            )
+            .with_status(201)
+            .create();
+
+        let cancellation = Cancellation::new();
+        cancellation.cancel(
+            CancelReason::Superseded(
+                "0b1c2d3e4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9c".to_owned()
+            )
+        );
+
+        assert_eq!(
+            advance(
+                &mut tracker,
+                &config,
+                &TrackerStore::in_memory(),
+                &Registry::new(),
+                &cancellation,
+                &HostLimiter::new(1)
+            ),
+            None
+        );
+
+        github.assert();
+    }
+
+    #[test]
+    fn notify_posts_status_and_stops_finished_tracker() {
+        let registry = Registry::new();
+
+        let mut tracker = pull_request_tracker(1, "f4c3b2a1d0e9f8a7b6c5d4e3f2a1b0c9d8e7f6a5");
+        tracker.commit_ref.owner = "notified-owner".to_owned();
+        let cancellation = registry.register(&tracker)
+            .expect("Failed to register tracker");
+
+        let other = registry.register(&pull_request_tracker(2, "5c6e1b2"))
+            .expect("Failed to register tracker");
+
+        let github = mock(
+                "POST",
+                "/repos/notified-owner/Hello-World/statuses/f4c3b2a1d0e9f8a7b6c5d4e3f2a1b0c9d8e7f6a5"
+            )
+            .match_body(Matcher::Regex(r#""state":"success""#.to_owned()))
+            .with_status(201)
+            .create();
+
+        let notification = Notification::new(r#"{
+            "build": {
+                "full_url": "http://jenkins.example.com/job/Hello-World-branches/3/",
+                "phase": "COMPLETED",
+                "status": "SUCCESS",
+                "scm": { "commit": "f4c3b2a1d0e9f8a7b6c5d4e3f2a1b0c9d8e7f6a5" }
+            }
+        }"#).expect("Failed to parse notification");
+
+        let updated = notify(
+            &notification,
+            &test_config(),
+            &TrackerStore::in_memory(),
+            &registry
+        ).expect("Failed to notify trackers");
+
+        assert_eq!(updated, 1);
+        assert_eq!(cancellation.reason(), Some(CancelReason::Finished));
+        assert_eq!(other.reason(), None);
+        github.assert();
+    }
+
+    #[test]
+    fn notify_skips_trackers_of_other_builds() {
+        let registry = Registry::new();
+        let sha = "0a9b8c7d6e5f4a3b2c1d0e9f8a7b6c5d4e3f2a1b";
+
+        let mut found = pull_request_tracker(1, sha);
+        found.build_url = Some(
+            "http://jenkins.example.com/job/Hello-World-branches/2/".to_owned()
+        );
+        let found = registry.register(&found)
+            .expect("Failed to register tracker");
+
+        let mut searching = pull_request_tracker(2, sha);
+        searching.commit_ref.owner = "hubot".to_owned();
+        let searching = registry.register(&searching)
+            .expect("Failed to register tracker");
+
+        let notification = Notification::new(r#"{
+            "build": {
+                "full_url": "http://jenkins.example.com/job/Hello-World-nightly/8/",
+                "phase": "COMPLETED",
+                "status": "FAILURE",
+                "scm": { "commit": "0a9b8c7d6e5f4a3b2c1d0e9f8a7b6c5d4e3f2a1b" }
+            }
+        }"#).expect("Failed to parse notification");
+
+        let updated = notify(
+            &notification,
+            &test_config(),
+            &TrackerStore::in_memory(),
+            &registry
+        ).expect("Failed to notify trackers");
+
+        assert_eq!(updated, 0);
+        assert_eq!(found.reason(), None);
+        assert_eq!(searching.reason(), None);
+    }
+
+    #[test]
+    fn is_build_of_checks_job_path() {
+        let build_url = "http://example.com/jenkins/job/octocat/job/Hello-World/job/PR-9/4/";
+        let jenkins_url = "http://example.com/jenkins/";
+
+        assert!(is_build_of(build_url, jenkins_url, "/job/octocat/job/Hello-World/job/PR-9"));
+        assert!(!is_build_of(build_url, jenkins_url, "/job/octocat/job/Hello-World"));
+        assert!(!is_build_of(build_url, jenkins_url, "/job/Hello-World/job/PR-9"));
+        assert!(!is_build_of(
+            "http://example.com/jenkins/job/Hello-World-branches/",
+            jenkins_url,
+            "/job/Hello-World-branches"
+        ));
+    }
+}